@@ -0,0 +1,215 @@
+use flate2::read::GzDecoder;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// The most a member's buffer is allocated up front. The size an archive
+/// declares for a member can't be trusted, so larger members grow as they're read.
+const MAX_PREALLOC: usize = 1 << 20;
+
+/// The regular files of a `.tar.gz`, `.tgz`, `.tar` or `.zip` archive, read
+/// into memory so they can be aggregated without extracting anything to disk.
+pub struct Archive {
+    /// Members keyed by their path inside the archive
+    members: BTreeMap<PathBuf, Member>,
+}
+
+struct Member {
+    size: u64,
+    /// Left empty for a member over the size limit, which is never read
+    bytes: Vec<u8>,
+}
+
+impl Archive {
+    /// Reads every regular file in the archive at `path`, choosing the format
+    /// from its extension. Directories, links and members whose paths would
+    /// lead outside the archive (absolute or containing `..`) are left out.
+    /// Members larger than `max_size` are listed but not read.
+    pub fn open(path: &Path, max_size: Option<u64>) -> io::Result<Self> {
+        let name = path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let file = BufReader::new(File::open(path)?);
+        let members = if name.ends_with(".zip") {
+            read_zip(file, max_size)?
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            read_tar(GzDecoder::new(file), max_size)?
+        } else if name.ends_with(".tar") {
+            read_tar(file, max_size)?
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unsupported archive type (expected .tar.gz, .tgz, .tar or .zip)",
+            ));
+        };
+        Ok(Self { members })
+    }
+
+    /// Member paths with their sizes, in path order.
+    pub fn files(&self) -> impl Iterator<Item = (&Path, u64)> {
+        self.members.iter().map(|(path, member)| (path.as_path(), member.size))
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.members.contains_key(path)
+    }
+
+    /// Whether `path` is a directory in the archive: some member lies beneath
+    /// it. The empty path is the archive's root.
+    pub fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty() || self.members.keys().any(|member| member != path && member.starts_with(path))
+    }
+
+    pub fn size(&self, path: &Path) -> Option<u64> {
+        self.members.get(path).map(|member| member.size)
+    }
+
+    pub fn read(&self, path: &Path) -> Option<&[u8]> {
+        self.members.get(path).map(|member| member.bytes.as_slice())
+    }
+}
+
+fn read_tar(reader: impl Read, max_size: Option<u64>) -> io::Result<BTreeMap<PathBuf, Member>> {
+    let mut members = BTreeMap::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let Some(path) = member_path(&entry.path()?) else {
+            continue;
+        };
+        let size = entry.size();
+        members.insert(path, read_member(&mut entry, size, max_size)?);
+    }
+    Ok(members)
+}
+
+fn read_zip(reader: impl Read + io::Seek, max_size: Option<u64>) -> io::Result<BTreeMap<PathBuf, Member>> {
+    let mut members = BTreeMap::new();
+    let mut archive = zip::ZipArchive::new(reader).map_err(io::Error::other)?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(io::Error::other)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let Some(path) = entry.enclosed_name().and_then(|path| member_path(&path)) else {
+            continue;
+        };
+        let size = entry.size();
+        members.insert(path, read_member(&mut entry, size, max_size)?);
+    }
+    Ok(members)
+}
+
+/// Reads a member unless its declared `size` is over `max_size`. Reading
+/// stops just past the limit, in case the declared size was wrong.
+fn read_member(entry: &mut impl Read, size: u64, max_size: Option<u64>) -> io::Result<Member> {
+    if max_size.is_some_and(|max_size| size > max_size) {
+        return Ok(Member { size, bytes: Vec::new() });
+    }
+    let mut bytes = Vec::with_capacity((size as usize).min(MAX_PREALLOC));
+    match max_size {
+        Some(max_size) => entry.take(max_size + 1).read_to_end(&mut bytes)?,
+        None => entry.read_to_end(&mut bytes)?,
+    };
+    let size = bytes.len() as u64;
+    if max_size.is_some_and(|max_size| size > max_size) {
+        bytes = Vec::new();
+    }
+    Ok(Member { size, bytes })
+}
+
+/// A member's path with any `./` prefix dropped, or `None` if it isn't a
+/// plain relative path.
+fn member_path(path: &Path) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!relative.as_os_str().is_empty()).then_some(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    fn tar_of(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, bytes) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *bytes).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn zip_of(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (path, bytes) in members {
+            writer.start_file(*path, zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(bytes).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn tar_members_are_read_by_path() {
+        let tar = tar_of(&[("./project/src/main.rs", b"fn main() {}\n"), ("project/README.md", b"# hi\n")]);
+        let members = read_tar(tar.as_slice(), None).unwrap();
+        let main = &members[Path::new("project/src/main.rs")];
+        assert_eq!(main.bytes, b"fn main() {}\n");
+        assert_eq!(main.size, 13);
+        assert!(members.contains_key(Path::new("project/README.md")));
+    }
+
+    #[test]
+    fn zip_members_are_read_by_path() {
+        let zip = zip_of(&[("project/src/main.rs", b"fn main() {}\n")]);
+        let members = read_zip(Cursor::new(zip), None).unwrap();
+        assert_eq!(members[Path::new("project/src/main.rs")].bytes, b"fn main() {}\n");
+    }
+
+    #[test]
+    fn members_over_the_size_limit_are_listed_but_not_read() {
+        let tar = tar_of(&[("small.txt", b"abc"), ("big.txt", &[b'x'; 100])]);
+        let members = read_tar(tar.as_slice(), Some(10)).unwrap();
+        assert_eq!(members[Path::new("small.txt")].bytes, b"abc");
+        let big = &members[Path::new("big.txt")];
+        assert_eq!(big.size, 100);
+        assert!(big.bytes.is_empty());
+
+        let zip = zip_of(&[("small.txt", b"abc"), ("big.txt", &[b'x'; 100])]);
+        let members = read_zip(Cursor::new(zip), Some(10)).unwrap();
+        assert_eq!(members[Path::new("small.txt")].bytes, b"abc");
+        assert!(members[Path::new("big.txt")].bytes.is_empty());
+    }
+
+    #[test]
+    fn a_wrong_declared_size_is_not_trusted() {
+        let mut short = &b"only a few bytes"[..];
+        let member = read_member(&mut short, u64::MAX, None).unwrap();
+        assert_eq!(member.size, 16);
+        assert_eq!(member.bytes, b"only a few bytes");
+
+        let mut long = &[b'x'; 100][..];
+        let member = read_member(&mut long, 5, Some(10)).unwrap();
+        assert_eq!(member.size, 11);
+        assert!(member.bytes.is_empty());
+    }
+
+    #[test]
+    fn paths_leading_outside_are_rejected() {
+        assert_eq!(member_path(Path::new("./a/b.rs")), Some(PathBuf::from("a/b.rs")));
+        assert_eq!(member_path(Path::new("../etc/passwd")), None);
+        assert_eq!(member_path(Path::new("/etc/passwd")), None);
+        assert_eq!(member_path(Path::new(".")), None);
+    }
+}
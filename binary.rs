@@ -0,0 +1,145 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// How much of a file is inspected when deciding whether it is binary.
+const SAMPLE_SIZE: usize = 8 * 1024;
+
+/// Share of control characters above which a sample is treated as binary.
+/// Text rarely has any besides tabs, line breaks and ANSI escapes, while
+/// binary data without a NUL is about a tenth control bytes.
+const MAX_CONTROL_RATIO: f64 = 0.05;
+
+/// Reads the start of a file and reports whether it looks binary.
+pub fn is_binary_file(path: &Path) -> io::Result<bool> {
+    Ok(is_binary(&read_sample(path)?))
+}
+
+/// Like `is_binary_file`, for content already in memory: only the same
+/// leading sample is inspected.
+pub fn looks_binary(contents: &[u8]) -> bool {
+    is_binary(&contents[..contents.len().min(SAMPLE_SIZE)])
+}
+
+/// Whether the start of a file is valid UTF-8, so it can be streamed
+/// without decoding.
+pub fn starts_as_utf8(path: &Path) -> io::Result<bool> {
+    let sample = read_sample(path)?;
+    Ok(match std::str::from_utf8(&sample) {
+        Ok(_) => true,
+        // A multi-byte character cut off by the end of the sample is fine
+        Err(e) => e.error_len().is_none(),
+    })
+}
+
+fn read_sample(path: &Path) -> io::Result<Vec<u8>> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    File::open(path)?
+        .take(SAMPLE_SIZE as u64)
+        .read_to_end(&mut sample)?;
+    Ok(sample)
+}
+
+/// Returns true if `sample` contains a null byte or a high ratio of control
+/// characters. Bytes that aren't valid UTF-8 don't count: they're what text
+/// in a legacy encoding like Latin-1 or Shift-JIS is made of, and it's left
+/// to the decoder to make sense of them.
+pub fn is_binary(sample: &[u8]) -> bool {
+    if sample.is_empty() {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+
+    let control = sample
+        .iter()
+        .filter(|&&byte| (byte < 0x20 && !matches!(byte, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b)) || byte == 0x7f)
+        .count();
+    control as f64 / sample.len() as f64 > MAX_CONTROL_RATIO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1252};
+
+    #[test]
+    fn empty_and_plain_text_are_not_binary() {
+        assert!(!is_binary(b""));
+        assert!(!is_binary(b"fn main() {\n\tprintln!(\"hi\");\r\n}\n"));
+        assert!(!is_binary("caf\u{e9} na\u{ef}ve \u{2014} UTF-8".as_bytes()));
+    }
+
+    #[test]
+    fn legacy_encoded_text_is_not_binary() {
+        let (latin1, _, _) = WINDOWS_1252.encode("caf\u{e9}");
+        assert!(!is_binary(&latin1));
+
+        let french = "L'\u{e9}t\u{e9} dernier, nous sommes all\u{e9}s \u{e0} la for\u{ea}t pr\u{e8}s du ch\u{e2}teau.\n";
+        let (french, _, _) = WINDOWS_1252.encode(french);
+        assert!(std::str::from_utf8(&french).is_err());
+        assert!(!is_binary(&french));
+
+        let german = "Gr\u{fc}\u{df}e aus M\u{fc}nchen, sch\u{f6}ne Gr\u{fc}\u{df}e!\n".repeat(50);
+        let (german, _, _) = WINDOWS_1252.encode(&german);
+        assert!(!is_binary(&german));
+
+        let (japanese, _, _) = SHIFT_JIS.encode("\u{65e5}\u{672c}\u{8a9e}\u{306e}\u{30c6}\u{30ad}\u{30b9}\u{30c8}\u{3067}\u{3059}\u{3002}\n");
+        assert!(std::str::from_utf8(&japanese).is_err());
+        assert!(!is_binary(&japanese));
+    }
+
+    #[test]
+    fn ansi_escapes_are_text() {
+        assert!(!is_binary(b"\x1b[31merror\x1b[0m: failed\n\x1b[32mok\x1b[0m\n"));
+    }
+
+    #[test]
+    fn null_bytes_mean_binary() {
+        assert!(is_binary(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert!(is_binary(b"text\0"));
+    }
+
+    #[test]
+    fn control_characters_past_the_ratio_mean_binary() {
+        // 1 in 20 is exactly the limit, 1 in 10 is over it
+        let at_limit: Vec<u8> = (0..100).map(|i| if i % 20 == 0 { 0x01 } else { b'a' }).collect();
+        assert!(!is_binary(&at_limit));
+        let over: Vec<u8> = (0..100).map(|i| if i % 10 == 0 { 0x01 } else { b'a' }).collect();
+        assert!(is_binary(&over));
+    }
+
+    #[test]
+    fn files_are_judged_by_their_first_bytes() {
+        let dir = std::env::temp_dir().join(format!("agg-files-binary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = dir.join("text.txt");
+        let image = dir.join("image.png");
+        let cut = dir.join("cut.txt");
+        std::fs::write(&text, "plain text\n").unwrap();
+        std::fs::write(&image, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        // A two-byte character split by the end of the sample
+        let mut contents = vec![b'a'; SAMPLE_SIZE - 1];
+        contents.extend_from_slice("\u{e9}".as_bytes());
+        std::fs::write(&cut, &contents).unwrap();
+
+        assert!(!is_binary_file(&text).unwrap());
+        assert!(is_binary_file(&image).unwrap());
+        assert!(starts_as_utf8(&text).unwrap());
+        assert!(starts_as_utf8(&cut).unwrap());
+        let (latin1, _, _) = WINDOWS_1252.encode("caf\u{e9}\n");
+        std::fs::write(&text, &latin1).unwrap();
+        assert!(!starts_as_utf8(&text).unwrap());
+        assert!(is_binary_file(&dir.join("missing")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn looks_binary_inspects_only_the_sample() {
+        let mut contents = vec![b'a'; SAMPLE_SIZE];
+        contents.push(0);
+        assert!(!looks_binary(&contents));
+        assert!(looks_binary(&contents[SAMPLE_SIZE - 10..]));
+    }
+}
@@ -1,78 +1,984 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
+use encoding_rs::Encoding;
+use regex::{Regex, RegexBuilder};
+
+use crate::config::Config;
+use crate::since::{parse_duration, parse_since};
+use crate::size::parse_size;
+
+/// How long a `--url` download is reused before checking for new commits
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Plain,
+    Json,
+    /// One JSON object per line, written as each file is processed
+    JsonLines,
+    Markdown,
+    Xml,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "plain" | "text" => Some(Self::Plain),
+            "json" => Some(Self::Json),
+            "jsonl" | "json-lines" | "ndjson" => Some(Self::JsonLines),
+            "markdown" | "md" => Some(Self::Markdown),
+            "xml" => Some(Self::Xml),
+            _ => None,
+        }
+    }
+
+    /// JSON, JSON Lines and XML have to stay machine-parseable, so nothing
+    /// but file data may go into them.
+    pub fn is_structured(self) -> bool {
+        matches!(self, Self::Json | Self::JsonLines | Self::Xml)
+    }
+
+    /// Whether files are JSON objects, whose content is decoded lossily.
+    pub fn is_json(self) -> bool {
+        matches!(self, Self::Json | Self::JsonLines)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    /// Alphabetical by relative path (the default)
+    Path,
+    /// Alphabetical by file name, then by path
+    Name,
+    /// Largest first, listing paths with their sizes
+    Size,
+}
+
+impl SortOrder {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "path" => Some(Self::Path),
+            "name" => Some(Self::Name),
+            "size" => Some(Self::Size),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct CliArgs {
     pub recursive: bool,
     pub ignore_gitignore: bool,
+    /// Leave out paths marked `export-ignore` in `.gitattributes`
+    pub respect_export_ignore: bool,
     pub patterns: Vec<String>,
-    pub github_url: Option<String>,
+    /// Line ranges given as `path:start-end`, keyed by the pattern without the range
+    pub line_ranges: HashMap<String, (usize, usize)>,
+    /// GitHub URLs to aggregate, in order, each with its own patterns pass
+    pub github_url: Vec<String>,
+    /// Archive whose members are aggregated in place of the working directory
+    pub archive: Option<PathBuf>,
+    /// Directory to compare the working directory against (`--diff`)
+    pub diff: Option<PathBuf>,
+    pub refresh: bool,
+    pub cache_ttl: Duration,
     pub show_version: bool,
     pub files_only: bool,
-    pub sort_by_size: bool,
+    pub sort: SortOrder,
+    pub output: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    /// Add to the `--output` file instead of replacing it, skipping files its `--manifest` lists
+    pub append_output: bool,
+    pub output_dir: Option<PathBuf>,
+    pub toc: bool,
+    pub prepend: Option<PathBuf>,
+    pub append: Option<PathBuf>,
+    pub hash: bool,
+    pub buffered: bool,
+    pub gzip: bool,
+    pub format: OutputFormat,
+    format_set: bool,
+    pub count_tokens: bool,
+    pub max_file_size: Option<u64>,
+    pub min_file_size: Option<u64>,
+    /// Skip files with more lines than this
+    pub max_lines: Option<usize>,
+    pub max_total_bytes: Option<u64>,
+    pub max_files: Option<usize>,
+    pub split: Option<u64>,
+    pub include_binary: bool,
+    pub line_numbers: bool,
+    pub head: Option<usize>,
+    /// Hard-wrap emitted lines longer than this many characters
+    pub wrap: Option<usize>,
+    pub strip_comments: bool,
+    pub strip_block_comments: bool,
+    pub strip_blank_lines: bool,
+    /// Convert CRLF and lone CR line endings to LF
+    pub normalize_newlines: bool,
+    /// Decode every file with this encoding instead of detecting it
+    pub encoding: Option<&'static Encoding>,
+    pub tail: Option<usize>,
+    pub jobs: Option<usize>,
+    pub excludes: Vec<String>,
+    /// Directory names (or globs) never descended into, at any depth
+    pub exclude_dirs: Vec<String>,
+    pub dry_run: bool,
+    pub count_only: bool,
+    pub stats: bool,
+    pub relative_to: Option<PathBuf>,
+    pub flatten: bool,
+    pub modified_since: Option<SystemTime>,
+    /// Lowercased extensions without the leading dot
+    pub extensions: HashSet<String>,
+    /// Only files whose content matches, from `--contains`
+    pub contains: Option<Regex>,
+    pub ignore_case: bool,
+    pub match_basename: bool,
+    /// Match glob patterns against absolute paths instead of relative ones
+    pub abs_patterns: bool,
+    pub follow_symlinks: bool,
+    pub include_hidden: bool,
+    pub include_git: bool,
+    pub skip_generated: bool,
+    pub max_depth: Option<usize>,
+    pub fail_fast: bool,
+    pub clipboard: bool,
+    pub watch: bool,
+    pub tree: bool,
+    /// List empty directories in `--tree` and `--dry-run`
+    pub emit_empty_dirs: bool,
+    /// Start the output with the repository name, git branch, commit and time
+    pub context_header: bool,
+    pub progress: bool,
+    pub group_by_ext: bool,
+    pub from_stdin: bool,
+    /// Paths read from stdin with `--from-stdin`, filled in by `main`
+    pub stdin_paths: Vec<PathBuf>,
+    pub changed_since: Option<String>,
+    /// Canonical paths changed since `--changed-since`, filled in by `main`
+    pub changed_files: Option<HashSet<PathBuf>>,
+    pub tracked_only: bool,
+    /// Canonical paths of the files git tracks, filled in by `main` for `--tracked-only`
+    pub tracked_files: Option<HashSet<PathBuf>>,
+    pub print0: bool,
+    /// Separator between files in plain output; `None` uses the default
+    pub separator: Option<String>,
+    pub header_template: Option<String>,
+    /// Directory of `header.tmpl`, `file.tmpl` and `footer.tmpl` for plain output
+    pub template_dir: Option<PathBuf>,
+    pub comment_headers: bool,
+    pub no_config: bool,
+    /// Config file read instead of `.agg-files` (`--config`)
+    pub config_path: Option<PathBuf>,
+    pub verbose: bool,
+    pub quiet: bool,
+    pub no_color: bool,
 }
 
 impl CliArgs {
-    pub fn parse() -> Self {
-        let args: Vec<String> = env::args().collect();
+    /// Parses the process's command line, after any `AGG_FILES_OPTS` defaults.
+    pub fn parse() -> Result<Self, String> {
+        Self::parse_from(with_default_opts(env::args().collect(), env::var("AGG_FILES_OPTS").ok().as_deref()))
+    }
+
+    /// Parses `args` as if given on the command line; the first one is the
+    /// program name, as with `env::args`. Fails with a message for the user
+    /// on an invalid value or a conflicting combination of flags.
+    pub fn parse_from<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut quiet = false;
+        // Printed after the loop, once a later --quiet is known
+        let mut warnings = Vec::new();
         let mut recursive = false;
         let mut ignore_gitignore = false;
+        let mut respect_export_ignore = false;
         let mut patterns = Vec::new();
-        let mut github_url = None;
+        let mut line_ranges = HashMap::new();
+        let mut github_url = Vec::new();
+        let mut refresh = false;
+        let mut cache_ttl = DEFAULT_CACHE_TTL;
         let mut show_version = false;
         let mut i = 1;
         let mut files_only = false;
-        let mut sort_by_size = false;
+        let mut sort = SortOrder::Path;
+        let mut output = None;
+        let mut manifest = None;
+        let mut append_output = false;
+        let mut output_dir = None;
+        let mut toc = false;
+        let mut prepend = None;
+        let mut append = None;
+        let mut hash = false;
+        let mut buffered = false;
+        let mut gzip = false;
+        let mut format = OutputFormat::Plain;
+        let mut format_set = false;
+        let mut count_tokens = false;
+        let mut max_file_size = None;
+        let mut max_lines = None;
+        let mut min_file_size = None;
+        let mut max_total_bytes = None;
+        let mut max_files = None;
+        let mut split = None;
+        let mut include_binary = false;
+        let mut line_numbers = false;
+        let mut head = None;
+        let mut wrap = None;
+        let mut strip_comments = false;
+        let mut strip_block_comments = false;
+        let mut strip_blank_lines = false;
+        let mut normalize_newlines = false;
+        let mut encoding = None;
+        let mut tail = None;
+        let mut jobs = None;
+        let mut excludes = Vec::new();
+        let mut exclude_dirs = Vec::new();
+        let mut dry_run = false;
+        let mut count_only = false;
+        let mut stats = false;
+        let mut relative_to = None;
+        let mut flatten = false;
+        let mut modified_since = None;
+        let mut contains = None;
+        let mut extensions = HashSet::new();
+        let mut ignore_case = false;
+        let mut match_basename = false;
+        let mut abs_patterns = false;
+        let mut follow_symlinks = false;
+        let mut include_hidden = false;
+        let mut include_git = false;
+        let mut skip_generated = false;
+        let mut max_depth = None;
+        let mut fail_fast = false;
+        let mut clipboard = false;
+        let mut watch = false;
+        let mut tree = false;
+        let mut emit_empty_dirs = false;
+        let mut context_header = false;
+        let mut progress = false;
+        let mut group_by_ext = false;
+        let mut from_stdin = false;
+        let mut changed_since = None;
+        let mut tracked_only = false;
+        let mut archive = None;
+        let mut diff = None;
+        let mut print0 = false;
+        let mut separator = None;
+        let mut header_template = None;
+        let mut template_dir = None;
+        let mut comment_headers = false;
+        let mut no_config = false;
+        let mut config_path = None;
+        let mut verbose = false;
+        let mut no_color = false;
 
         while i < args.len() {
             match args[i].as_str() {
                 "-r" => recursive = true,
                 "-i" => ignore_gitignore = true,
+                "--respect-export-ignore" => respect_export_ignore = true,
+                "--no-config" => no_config = true,
+                "--config" => {
+                    if i + 1 < args.len() {
+                        config_path = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--verbose" => verbose = true,
+                "-q" | "--quiet" => quiet = true,
+                "--no-color" => no_color = true,
                 "--files-only" => files_only = true,
+                "--dry-run" => dry_run = true,
+                "--count-only" => count_only = true,
+                "--ignore-case" => ignore_case = true,
+                "--match-basename" => match_basename = true,
+                "--abs-patterns" => abs_patterns = true,
+                "-L" | "--follow-symlinks" => follow_symlinks = true,
+                "-H" | "--include-hidden" => include_hidden = true,
+                "--include-git" => include_git = true,
+                "--skip-generated" => skip_generated = true,
+                "--stats" => stats = true,
+                "--hash" => hash = true,
+                "--fail-fast" => fail_fast = true,
+                "-c" | "--clipboard" => clipboard = true,
+                "-w" | "--watch" => watch = true,
+                "--tree" => tree = true,
+                "--emit-empty-dirs" => emit_empty_dirs = true,
+                "--context-header" => context_header = true,
+                "--progress" => progress = true,
+                "--group-by-ext" => group_by_ext = true,
+                "--flatten" => flatten = true,
+                "--comment-headers" => comment_headers = true,
+                "--from-stdin" | "-" => from_stdin = true,
+                "-0" | "--print0" => print0 = true,
                 "-v" | "--version" => show_version = true,
-                "--sort-size" => sort_by_size = true,
+                "--sort-size" => {
+                    warnings.push("--sort-size is deprecated, use --sort size");
+                    sort = SortOrder::Size;
+                }
+                "--sort" => {
+                    if i + 1 < args.len() {
+                        sort = SortOrder::parse(&args[i + 1])
+                            .ok_or_else(|| invalid_value("--sort", &args[i + 1]))?;
+                        i += 1;
+                    }
+                }
+                "--buffered" => buffered = true,
+                "-z" | "--gzip" => gzip = true,
+                "--count-tokens" => count_tokens = true,
+                "--include-binary" => include_binary = true,
+                "-n" | "--line-numbers" => line_numbers = true,
+                "--strip-comments" => strip_comments = true,
+                "--strip-block-comments" => {
+                    // Block comments are only stripped on top of line comments
+                    strip_comments = true;
+                    strip_block_comments = true;
+                }
+                "--strip-blank-lines" => strip_blank_lines = true,
+                "--normalize-newlines" => normalize_newlines = true,
+                "--encoding" => {
+                    if i + 1 < args.len() {
+                        encoding = Some(
+                            Encoding::for_label(args[i + 1].as_bytes())
+                                .ok_or_else(|| invalid_value("--encoding", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "-o" | "--output" => {
+                    if i + 1 < args.len() {
+                        output = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--toc" => toc = true,
+                "--output-dir" => {
+                    if i + 1 < args.len() {
+                        output_dir = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--prepend" => {
+                    if i + 1 < args.len() {
+                        prepend = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--append" => {
+                    if i + 1 < args.len() {
+                        append = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--append-output" => append_output = true,
+                "--manifest" => {
+                    if i + 1 < args.len() {
+                        manifest = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--format" => {
+                    if i + 1 < args.len() {
+                        format = OutputFormat::parse(&args[i + 1])
+                            .ok_or_else(|| invalid_value("--format", &args[i + 1]))?;
+                        format_set = true;
+                        i += 1;
+                    }
+                }
+                "--max-file-size" => {
+                    if i + 1 < args.len() {
+                        max_file_size = Some(
+                            parse_size(&args[i + 1])
+                                .ok_or_else(|| invalid_value("--max-file-size", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--max-lines" => {
+                    if i + 1 < args.len() {
+                        max_lines = Some(
+                            args[i + 1]
+                                .parse()
+                                .map_err(|_| invalid_value("--max-lines", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--min-file-size" => {
+                    if i + 1 < args.len() {
+                        min_file_size = Some(
+                            parse_size(&args[i + 1])
+                                .ok_or_else(|| invalid_value("--min-file-size", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--max-total-bytes" => {
+                    if i + 1 < args.len() {
+                        max_total_bytes = Some(
+                            parse_size(&args[i + 1])
+                                .ok_or_else(|| invalid_value("--max-total-bytes", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--max-files" => {
+                    if i + 1 < args.len() {
+                        max_files = Some(
+                            args[i + 1]
+                                .parse()
+                                .map_err(|_| invalid_value("--max-files", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--split" => {
+                    if i + 1 < args.len() {
+                        split = Some(
+                            parse_size(&args[i + 1])
+                                .filter(|&size| size > 0)
+                                .ok_or_else(|| invalid_value("--split", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--head" => {
+                    if i + 1 < args.len() {
+                        head = Some(
+                            args[i + 1]
+                                .parse()
+                                .map_err(|_| invalid_value("--head", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--tail" => {
+                    if i + 1 < args.len() {
+                        tail = Some(
+                            args[i + 1]
+                                .parse()
+                                .map_err(|_| invalid_value("--tail", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--wrap" => {
+                    if i + 1 < args.len() {
+                        wrap = Some(
+                            args[i + 1]
+                                .parse()
+                                .ok()
+                                .filter(|&cols: &usize| cols > 0)
+                                .ok_or_else(|| invalid_value("--wrap", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "-j" | "--jobs" => {
+                    if i + 1 < args.len() {
+                        jobs = Some(
+                            args[i + 1]
+                                .parse()
+                                .map_err(|_| invalid_value("--jobs", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--exclude" => {
+                    if i + 1 < args.len() {
+                        excludes.push(args[i + 1].clone());
+                        i += 1;
+                    }
+                }
+                "--exclude-dir" => {
+                    if i + 1 < args.len() {
+                        exclude_dirs.push(args[i + 1].clone());
+                        i += 1;
+                    }
+                }
+                "--relative-to" => {
+                    if i + 1 < args.len() {
+                        relative_to = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--modified-since" => {
+                    if i + 1 < args.len() {
+                        modified_since = Some(
+                            parse_since(&args[i + 1], SystemTime::now())
+                                .ok_or_else(|| invalid_value("--modified-since", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--contains" => {
+                    if i + 1 < args.len() {
+                        contains = Some(args[i + 1].clone());
+                        i += 1;
+                    }
+                }
+                "--ext" => {
+                    if i + 1 < args.len() {
+                        extensions.extend(
+                            args[i + 1]
+                                .split(',')
+                                .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+                                .filter(|ext| !ext.is_empty()),
+                        );
+                        i += 1;
+                    }
+                }
+                "--max-depth" => {
+                    if i + 1 < args.len() {
+                        max_depth = Some(
+                            args[i + 1]
+                                .parse()
+                                .map_err(|_| invalid_value("--max-depth", &args[i + 1]))?,
+                        );
+                        i += 1;
+                    }
+                }
+                "--separator" => {
+                    if i + 1 < args.len() {
+                        separator = Some(unescape(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--no-separator" => separator = Some(String::new()),
+                "--template-dir" => {
+                    if i + 1 < args.len() {
+                        template_dir = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--header-template" => {
+                    if i + 1 < args.len() {
+                        header_template = Some(unescape(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--tracked-only" => tracked_only = true,
+                "--changed-since" => {
+                    if i + 1 < args.len() {
+                        changed_since = Some(args[i + 1].clone());
+                        i += 1;
+                    }
+                }
                 "--url" => {
                     if i + 1 < args.len() {
-                        github_url = Some(args[i + 1].clone());
+                        github_url.push(args[i + 1].clone());
+                        i += 1;
+                    }
+                }
+                "--refresh" => refresh = true,
+                "--diff" => {
+                    if i + 1 < args.len() {
+                        diff = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--archive" => {
+                    if i + 1 < args.len() {
+                        archive = Some(PathBuf::from(&args[i + 1]));
+                        i += 1;
+                    }
+                }
+                "--cache-ttl" => {
+                    if i + 1 < args.len() {
+                        cache_ttl = parse_duration(args[i + 1].trim())
+                            .ok_or_else(|| invalid_value("--cache-ttl", &args[i + 1]))?;
                         i += 1;
                     }
                 }
                 _ => {
-                    if !args[i].starts_with('-') {
-                        patterns.push(args[i].clone());
+                    if let Some(path) = args[i].strip_prefix('@') {
+                        for arg in read_response_file(path)? {
+                            add_pattern(&arg, &mut patterns, &mut excludes, &mut line_ranges);
+                        }
+                    } else if !args[i].starts_with('-') {
+                        add_pattern(&args[i], &mut patterns, &mut excludes, &mut line_ranges);
                     }
                 }
             }
             i += 1;
         }
+        if !quiet {
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+        }
+
+        // Compiled after the loop so a later --ignore-case still applies
+        let contains = contains
+            .map(|pattern| {
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(ignore_case)
+                    .build()
+                    .map_err(|_| invalid_value("--contains", &pattern))
+            })
+            .transpose()?;
+
+        // NUL separators only make sense for path listings
+        if print0 && !files_only && !dry_run {
+            if !quiet {
+                eprintln!("Warning: --print0 has no effect without --files-only or --dry-run");
+            }
+            print0 = false;
+        }
+
+        // Empty directories are only shown in listings of the layout
+        if emit_empty_dirs && !tree && !dry_run {
+            if !quiet {
+                eprintln!("Warning: --emit-empty-dirs has no effect without --tree or --dry-run");
+            }
+            emit_empty_dirs = false;
+        }
+
+        // Chunks are named after the output file, and the clipboard can't hold several
+        if split.is_some() && output.is_none() {
+            return Err("--split requires --output to name the chunk files".to_string());
+        }
+        if split.is_some() && clipboard {
+            return Err("--split can't be combined with --clipboard".to_string());
+        }
+        // Each chunk is written as soon as it's full, so there's no single write to defer
+        if split.is_some() && buffered {
+            return Err("--buffered can't be combined with --split".to_string());
+        }
+        // Offsets are only useful into a file, and a chunked one has no single start
+        if toc && output.is_none() {
+            return Err("--toc requires --output".to_string());
+        }
+        if toc && split.is_some() {
+            return Err("--toc can't be combined with --split".to_string());
+        }
+        // The manifest is the record of what the file already holds
+        if append_output && (output.is_none() || manifest.is_none()) {
+            return Err("--append-output requires --output and --manifest".to_string());
+        }
+        if append_output && (split.is_some() || toc) {
+            let flag = if toc { "--toc" } else { "--split" };
+            return Err(format!("--append-output can't be combined with {}", flag));
+        }
+
+        // Archive members have no checkout, history or modification times to go by
+        if archive.is_some() {
+            let conflicting = [
+                ("--url", !github_url.is_empty()),
+                ("--from-stdin", from_stdin),
+                ("--watch", watch),
+                ("--changed-since", changed_since.is_some()),
+                ("--tracked-only", tracked_only),
+                ("--modified-since", modified_since.is_some()),
+                ("--diff", diff.is_some()),
+                ("--abs-patterns", abs_patterns),
+                ("--respect-export-ignore", respect_export_ignore),
+            ];
+            if let Some((flag, _)) = conflicting.iter().find(|(_, set)| *set) {
+                return Err(format!("--archive can't be combined with {}", flag));
+            }
+        }
 
-        // If no patterns specified and URL is provided, default to all files
-        if patterns.is_empty() && github_url.is_some() {
+        // Which files differ is only known once both sides are read
+        if diff.is_some() && (dry_run || count_only) {
+            let flag = if dry_run { "--dry-run" } else { "--count-only" };
+            return Err(format!("--diff can't be combined with {}", flag));
+        }
+
+        // Each source is a pass of its own into the shared output, so nothing
+        // that's built from every file at once can span them
+        if github_url.len() > 1 {
+            let conflicting = [
+                ("--watch", watch),
+                ("--toc", toc),
+                ("--manifest", manifest.is_some()),
+                ("--output-dir", output_dir.is_some()),
+                ("--template-dir", template_dir.is_some()),
+            ];
+            if let Some((flag, _)) = conflicting.iter().find(|(_, set)| *set) {
+                return Err(format!("Several --url sources can't be combined with {}", flag));
+            }
+            // The banner between sources would break structured output
+            if format.is_structured() {
+                return Err("Several --url sources can only be aggregated in plain or markdown format".to_string());
+            }
+        }
+
+        // If no patterns specified and URL or archive is provided, default to all files
+        if patterns.is_empty() && (!github_url.is_empty() || archive.is_some()) {
             patterns.push("*".to_string());
         }
 
-        Self {
+        Ok(Self {
             recursive,
             ignore_gitignore,
+            respect_export_ignore,
             patterns,
+            line_ranges,
             github_url,
+            archive,
+            diff,
+            refresh,
+            cache_ttl,
             show_version,
             files_only,
-            sort_by_size,
+            sort,
+            output,
+            manifest,
+            append_output,
+            output_dir,
+            toc,
+            prepend,
+            append,
+            hash,
+            buffered,
+            gzip,
+            format,
+            format_set,
+            count_tokens,
+            max_file_size,
+            max_lines,
+            min_file_size,
+            max_total_bytes,
+            max_files,
+            split,
+            include_binary,
+            line_numbers,
+            head,
+            wrap,
+            strip_comments,
+            strip_block_comments,
+            strip_blank_lines,
+            normalize_newlines,
+            encoding,
+            tail,
+            jobs,
+            excludes,
+            exclude_dirs,
+            dry_run,
+            count_only,
+            stats,
+            relative_to,
+            flatten,
+            modified_since,
+            extensions,
+            contains,
+            ignore_case,
+            match_basename,
+            abs_patterns,
+            follow_symlinks,
+            include_hidden,
+            include_git,
+            skip_generated,
+            max_depth,
+            fail_fast,
+            clipboard,
+            watch,
+            tree,
+            emit_empty_dirs,
+            context_header,
+            progress,
+            group_by_ext,
+            from_stdin,
+            stdin_paths: Vec::new(),
+            changed_since,
+            changed_files: None,
+            tracked_only,
+            tracked_files: None,
+            print0,
+            separator,
+            header_template,
+            template_dir,
+            comment_headers,
+            no_config,
+            config_path,
+            verbose,
+            quiet,
+            no_color,
+        })
+    }
+
+    /// Reads the `--from-stdin` file list: one path per line, with blank
+    /// lines skipped and `\r\n` line endings accepted.
+    pub fn read_path_list<R: BufRead>(reader: R) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if !line.is_empty() {
+                paths.push(PathBuf::from(line));
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Applies `--changed-since`. With patterns, only the matched files that
+    /// changed are kept; without any, the changed files themselves are
+    /// aggregated, as if they had been listed on stdin.
+    pub fn restrict_to_changed(&mut self, working_dir: &std::path::Path, files: Vec<PathBuf>) {
+        if self.patterns.is_empty() {
+            self.stdin_paths.extend(files);
+            return;
+        }
+        self.changed_files = Some(
+            files
+                .iter()
+                .filter_map(|file| std::fs::canonicalize(working_dir.join(file)).ok())
+                .collect(),
+        );
+    }
+
+    /// Applies `--tracked-only`: only matched files git tracks are kept.
+    pub fn restrict_to_tracked(&mut self, working_dir: &std::path::Path, files: Vec<PathBuf>) {
+        self.tracked_files = Some(
+            files
+                .iter()
+                .filter_map(|file| std::fs::canonicalize(working_dir.join(file)).ok())
+                .collect(),
+        );
+    }
+
+    /// The config these arguments call for: none with `--no-config`, else the
+    /// global file merged with `--config`, `AGG_FILES_CONFIG` or the nearest
+    /// `.agg-files`.
+    pub fn load_config(&self) -> Result<Config, String> {
+        if self.no_config {
+            return Ok(Config::default());
+        }
+        let explicit = self
+            .config_path
+            .clone()
+            .or_else(|| env::var_os("AGG_FILES_CONFIG").filter(|v| !v.is_empty()).map(PathBuf::from));
+        Config::load(explicit.as_deref())
+    }
+
+    /// Fills in defaults from the config for anything not given on the command line.
+    pub fn apply_config(&mut self, config: &Config) {
+        if self.patterns.is_empty() && self.github_url.is_empty() && !self.from_stdin && self.changed_since.is_none() {
+            if let Some(include) = &config.include {
+                self.patterns = include.clone();
+            }
+        }
+
+        if self.separator.is_none() {
+            self.separator = config.separator.clone();
+        }
+        if self.header_template.is_none() {
+            self.header_template = config.header_template.clone();
+        }
+
+        if !self.format_set {
+            if let Some(format) = &config.format {
+                self.format = OutputFormat::parse(format).unwrap_or_else(|| {
+                    if !self.quiet {
+                        eprintln!("Warning: unknown format '{}' in config, using plain", format);
+                    }
+                    OutputFormat::Plain
+                });
+            }
         }
     }
 
     pub fn is_valid(&self) -> bool {
-        self.show_version || !self.patterns.is_empty() || self.github_url.is_some()
+        self.show_version
+            || !self.patterns.is_empty()
+            || !self.github_url.is_empty()
+            || self.archive.is_some()
+            || self.from_stdin
+            || self.changed_since.is_some()
     }
 
     pub fn print_usage(&self) {
         let program_name = env::args().next().unwrap_or_else(|| String::from("program"));
         println!("Usage: {} [OPTIONS] [PATTERNS]", program_name);
         println!("\nOptions:");
-        println!("  --url <github_url>  GitHub repository URL");
+        println!("  --url <github_url>  GitHub repository URL (repeat to aggregate several in order)");
+        println!("  --refresh           Download the --url repository again even if it's cached");
+        println!("  --cache-ttl <time>  Reuse a cached --url download without checking GitHub for this long (default 1h)");
+        println!("  --archive <path>    Aggregate the files in a .tar.gz, .tgz, .tar or .zip without extracting it");
+        println!("  --diff <dir>        Emit a unified diff of each file that differs from the same file in dir");
         println!("  -r                  Search recursively");
-        println!("  -i                  Ignore .gitignore (include all files)");
+        println!("  --max-depth <n>     Descend at most n directory levels (overrides -r)");
+        println!("  -i                  Ignore .gitignore (.ignore and .aggignore still apply)");
+        println!("  --respect-export-ignore  Skip paths marked export-ignore in .gitattributes");
+        println!("  --no-config         Don't read .agg-files or the global config file");
+        println!("  --config <path>     Read this config file instead of .agg-files (also AGG_FILES_CONFIG)");
         println!("  --files-only        Only show file paths without content");
-        println!("  --sort-size         Sort files by content size (largest first)");
+        println!("  --dry-run           List the files that would be processed with totals, without reading them");
+        println!("  --count-only        Print only the total files, bytes and lines");
+        println!("  --sort <order>      Order files by path (default), name, or size (largest first)");
+        println!("  --sort-size         Deprecated alias for --sort size");
+        println!("  -o, --output <path> Write aggregated output to a file instead of stdout");
+        println!("  --hash              Show each file's SHA-256 in its header");
+        println!("  --toc               Start the --output file with each file's byte offset");
+        println!("  --output-dir <dir>  Write each processed file to the same path under dir instead");
+        println!("  --prepend <path>    Write this file's contents before the aggregated files");
+        println!("  --append <path>     Write this file's contents after the aggregated files");
+        println!("  --manifest <path>   Also write a JSON (or .yaml) list of the emitted files with hashes");
+        println!("  --append-output     Add to the --output file, skipping files its --manifest already lists");
+        println!("  --split <size>      Write the output as <output>.001, <output>.002, ... each at most size");
+        println!("  -z, --gzip          Gzip-compress the output (to the --output file or stdout)");
+        println!("  -c, --clipboard     Copy the aggregated output to the clipboard instead of stdout");
+        println!("  --buffered          Collect all output and write it at once when done");
+        println!("  --format <format>   Output format: plain (default), json, jsonl, markdown or xml");
+        println!("  --count-tokens      Report an approximate token count (per file with --files-only)");
+        println!("  --max-file-size <size>  Skip files larger than this (e.g. 500, 10k, 2M)");
+        println!("  --max-lines <n>     Skip files with more than n lines");
+        println!("  --min-file-size <size>  Skip files smaller than this (e.g. 1 to skip empty files)");
+        println!("  --max-total-bytes <size>  Stop adding files once the output would exceed this");
+        println!("  --max-files <n>     Stop after emitting n files");
+        println!("  --include-binary    Include binary files (decoded lossily) instead of skipping them");
+        println!("  -n, --line-numbers  Prefix each content line with its line number");
+        println!("  --strip-comments    Drop comment-only lines (heuristic)");
+        println!("  --strip-block-comments  Also drop block comments");
+        println!("  --strip-blank-lines Drop empty lines");
+        println!("  --normalize-newlines  Convert CRLF and CR line endings to LF");
+        println!("  --encoding <name>   Decode files with this encoding (e.g. latin1, shift_jis) instead of detecting it");
+        println!("  --head <n>          Emit only the first n lines of each file");
+        println!("  --tail <n>          Emit only the last n lines of each file (combines with --head)");
+        println!("  --wrap <cols>       Hard-wrap lines longer than cols characters, marking continuations with ↪");
+        println!("  -j, --jobs <n>      Number of threads used to read files (default: one per CPU)");
+        println!("  --exclude <glob>    Skip files matching the glob (repeatable, overrides patterns)");
+        println!("  --exclude-dir <name>  Never descend into directories with this name, at any depth (repeatable)");
+        println!("  --stats             Print file, byte and skip totals, by extension, to stderr when done");
+        println!("  --relative-to <dir> Show header paths relative to this directory");
+        println!("  --flatten           Show only file names in headers, numbering duplicates");
+        println!("  --modified-since <when>  Only include files modified since a duration ago (2d, 3h) or a date");
+        println!("  --contains <regex>  Only include files whose content matches the regex");
+        println!("  --ext <ext>         Only include files with this extension (repeatable, e.g. --ext rs,toml)");
+        println!("  --ignore-case       Match patterns, excludes and config ignores case-insensitively");
+        println!("  --match-basename    Match patterns without a / against file names only");
+        println!("  --abs-patterns      Match glob patterns against absolute paths (e.g. '/home/me/proj/src/*.rs')");
+        println!("  -L, --follow-symlinks  Descend into symlinked directories (loops are skipped)");
+        println!("  -H, --include-hidden   Include dotfiles and dot-directories (.git only with --include-git)");
+        println!("  --include-git       Don't skip .git directories");
+        println!("  --skip-generated    Skip lock files, minified bundles and other generated files");
+        println!("  --fail-fast         Stop at the first file that can't be read");
+        println!("  --changed-since <ref>  Only files that differ from a git ref (e.g. main)");
+        println!("  --tracked-only      Only files tracked by git (git ls-files)");
+        println!("  @<file>             Read patterns from a file, one per line (# for comments)");
+        println!("  --from-stdin, -     Read newline-separated file paths from stdin");
+        println!("  -0, --print0        Separate listed paths with NUL bytes");
+        println!("  --separator <text>  Line written between files in plain output");
+        println!("  --no-separator      Write nothing between files");
+        println!("  --header-template <template>");
+        println!("                      File header in plain output, e.g. '/* {{path}} ({{lines}} lines) */'");
+        println!("  --template-dir <dir>  Render plain output with file.tmpl (and header.tmpl, footer.tmpl) from dir");
+        println!("  --comment-headers   Write plain headers as comments in each file's language");
+        println!("  --tree              Print a directory tree of the included files first");
+        println!("  --emit-empty-dirs   Also show empty directories in --tree and --dry-run");
+        println!("  --context-header    Start with the repository name, git branch, commit and time");
+        println!("  --group-by-ext      Group files by language, each under a section header");
+        println!("  -w, --watch         Re-run whenever a matching file changes");
+        println!("  --no-color          Don't color headers, even in a terminal");
+        println!("  --progress          Show a progress bar on stderr while finding and reading files");
+        println!("  -q, --quiet         Silence warnings and per-file errors on stderr");
+        println!("  --verbose           Report each skipped path and why to stderr");
         println!("  -v, --version       Show version information");
         println!("\nExamples:");
         println!("  {} --url 'https://github.com/org/repo/tree/main/path' -r", program_name);
@@ -80,3 +986,258 @@ impl CliArgs {
         println!("  {} --version", program_name);
     }
 }
+
+fn invalid_value(flag: &str, value: &str) -> String {
+    format!("Invalid value for {}: {}", flag, value)
+}
+
+/// Inserts the `AGG_FILES_OPTS` defaults after the program name. They go
+/// first, so flags given on the command line win.
+fn with_default_opts(mut args: Vec<String>, opts: Option<&str>) -> Vec<String> {
+    if let Some(opts) = opts {
+        args.splice(1..1, split_words(opts));
+    }
+    args
+}
+
+/// Splits `AGG_FILES_OPTS` into arguments on whitespace. Single or double
+/// quotes group words containing spaces, as in a shell.
+fn split_words(value: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+
+    for c in value.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Adds a pattern argument. A leading `!` makes it an exclude instead, as in
+/// `.gitignore`; `\!` stands for a literal `!`.
+fn add_pattern(
+    arg: &str,
+    patterns: &mut Vec<String>,
+    excludes: &mut Vec<String>,
+    line_ranges: &mut HashMap<String, (usize, usize)>,
+) {
+    if let Some(exclude) = arg.strip_prefix('!') {
+        if !exclude.is_empty() {
+            excludes.push(exclude.to_string());
+        }
+        return;
+    }
+    let arg = arg.strip_prefix('\\').filter(|rest| rest.starts_with('!')).unwrap_or(arg);
+    let (pattern, range) = split_line_range(arg);
+    if let Some(range) = range {
+        line_ranges.insert(pattern.clone(), range);
+    }
+    patterns.push(pattern);
+}
+
+/// Reads the patterns in an `@file` argument: one per line, skipping blank
+/// lines and `#` comments. A missing file is a usage error.
+fn read_response_file(path: &str) -> Result<Vec<String>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("Error reading pattern file {}: {}", path, e))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Splits a trailing `:start-end` line range off a pattern, as in
+/// `src/main.rs:10-40`. Anything else is returned unchanged.
+fn split_line_range(arg: &str) -> (String, Option<(usize, usize)>) {
+    let range = arg.rsplit_once(':').and_then(|(pattern, range)| {
+        let (start, end) = range.split_once('-')?;
+        Some((pattern, (start.parse().ok()?, end.parse().ok()?)))
+    });
+    match range {
+        Some((pattern, range)) if !pattern.is_empty() => (pattern.to_string(), Some(range)),
+        _ => (arg.to_string(), None),
+    }
+}
+
+/// Expands `\n`, `\t` and `\\` in a command-line value, so multi-line
+/// separators and headers can be passed without shell-specific quoting.
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('\\') => result.push('\\'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliArgs, String> {
+        CliArgs::parse_from(std::iter::once("agg-files").chain(args.iter().copied()).map(String::from))
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        assert_eq!(parse(&["--max-lines", "x"]).err().unwrap(), "Invalid value for --max-lines: x");
+        assert_eq!(parse(&["--sort", "color"]).err().unwrap(), "Invalid value for --sort: color");
+        assert!(parse(&["--contains", "("]).is_err());
+        assert!(parse(&["@/nonexistent/patterns.txt"]).err().unwrap().starts_with("Error reading pattern file"));
+    }
+
+    #[test]
+    fn conflicting_flags_are_errors() {
+        assert_eq!(parse(&["--split", "1M"]).err().unwrap(), "--split requires --output to name the chunk files");
+        assert_eq!(
+            parse(&["--archive", "a.zip", "--watch"]).err().unwrap(),
+            "--archive can't be combined with --watch"
+        );
+        assert_eq!(
+            parse(&["-o", "out.txt", "--split", "1M", "--buffered"]).err().unwrap(),
+            "--buffered can't be combined with --split"
+        );
+    }
+
+    #[test]
+    fn several_urls_need_a_plain_or_markdown_format() {
+        let urls = ["--url", "https://github.com/org/one", "--url", "https://github.com/org/two"];
+        let with_format = |format: &str| parse(&[&urls[..], &["--format", format]].concat());
+        assert_eq!(
+            with_format("json").err().unwrap(),
+            "Several --url sources can only be aggregated in plain or markdown format"
+        );
+        assert!(with_format("xml").is_err());
+        assert!(with_format("markdown").is_ok());
+        assert!(parse(&["--url", "https://github.com/org/one", "--format", "json"]).is_ok());
+    }
+
+    #[test]
+    fn read_path_list_skips_blank_lines_and_carriage_returns() {
+        let paths = CliArgs::read_path_list("src/main.rs\r\n\nREADME.md\n".as_bytes()).unwrap();
+        assert_eq!(paths, [PathBuf::from("src/main.rs"), PathBuf::from("README.md")]);
+    }
+
+    #[test]
+    fn no_config_skips_every_config_file() {
+        let path = env::temp_dir().join(format!("agg-files-no-config-{}.yaml", std::process::id()));
+        std::fs::write(&path, "ignore:\n  - '*.log'\n").unwrap();
+        let config_path = path.to_str().unwrap();
+
+        let config = parse(&["--config", config_path]).unwrap().load_config().unwrap();
+        assert!(config.ignore.unwrap().contains(&"*.log".to_string()));
+        let config = parse(&["--no-config", "--config", config_path]).unwrap().load_config().unwrap();
+        assert!(config.ignore.is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn response_files_add_patterns() {
+        let path = env::temp_dir().join(format!("agg-files-patterns-{}.txt", std::process::id()));
+        std::fs::write(&path, "# sources\nsrc/**/*.rs\n\n  Cargo.toml  \n!target\nsrc/main.rs:1-5\n").unwrap();
+        let args = parse(&["README.md", &format!("@{}", path.display())]).unwrap();
+        assert_eq!(args.patterns, ["README.md", "src/**/*.rs", "Cargo.toml", "src/main.rs"]);
+        assert_eq!(args.excludes, ["target"]);
+        assert_eq!(args.line_ranges["src/main.rs"], (1, 5));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changed_files_replace_missing_patterns() {
+        let changed = vec![PathBuf::from("src/main.rs")];
+        let mut args = parse(&["--changed-since", "main"]).unwrap();
+        args.restrict_to_changed(std::path::Path::new("."), changed.clone());
+        assert_eq!(args.stdin_paths, changed);
+        assert!(args.changed_files.is_none());
+
+        // With patterns they only narrow what matches; missing files drop out
+        let mut args = parse(&["--changed-since", "main", "*.rs"]).unwrap();
+        args.restrict_to_changed(std::path::Path::new("/nonexistent"), changed);
+        assert!(args.stdin_paths.is_empty());
+        assert_eq!(args.changed_files, Some(HashSet::new()));
+    }
+
+    #[test]
+    fn default_opts_come_before_the_command_line() {
+        let command_line = ["agg-files", "--format", "json", "*.rs"].map(String::from).to_vec();
+        let args = with_default_opts(command_line, Some("-r --format markdown --exclude 'my target'"));
+        assert_eq!(args, ["agg-files", "-r", "--format", "markdown", "--exclude", "my target", "--format", "json", "*.rs"]);
+
+        let args = CliArgs::parse_from(args).unwrap();
+        assert!(args.recursive);
+        assert!(args.format.is_json());
+        assert_eq!(args.excludes, ["my target"]);
+        assert_eq!(args.patterns, ["*.rs"]);
+    }
+
+    #[test]
+    fn words_are_split_like_a_shell() {
+        assert_eq!(split_words("  -r  --sep \"a b\" x'y z'  "), ["-r", "--sep", "a b", "xy z"]);
+        assert_eq!(split_words("''"), [""]);
+        assert!(split_words("   ").is_empty());
+    }
+
+    #[test]
+    fn patterns_and_flags_are_parsed() {
+        let args = parse(&["-r", "*.rs", "!target", "src/main.rs:3-9"]).unwrap();
+        assert!(args.recursive);
+        assert_eq!(args.patterns, ["*.rs", "src/main.rs"]);
+        assert_eq!(args.excludes, ["target"]);
+        assert_eq!(args.line_ranges["src/main.rs"], (3, 9));
+    }
+
+    #[test]
+    fn negated_patterns_become_excludes() {
+        let args = parse(&["*.rs", "!*.lock", "\\!important.md", "!", "Cargo.toml", "!target"]).unwrap();
+        assert_eq!(args.patterns, ["*.rs", "!important.md", "Cargo.toml"]);
+        assert_eq!(args.excludes, ["*.lock", "target"]);
+        // Only a `\` before `!` is an escape
+        assert_eq!(parse(&["\\src"]).unwrap().patterns, ["\\src"]);
+    }
+
+    #[test]
+    fn quiet_is_only_a_flag_where_a_flag_is_expected() {
+        let args = parse(&["--separator", "-q", "a.txt"]).unwrap();
+        assert_eq!(args.separator.as_deref(), Some("-q"));
+        assert_eq!(args.patterns, ["a.txt"]);
+        assert!(!args.quiet);
+        assert!(parse(&["--sort-size", "-q", "a.txt"]).unwrap().quiet);
+        assert!(parse(&["--quiet"]).unwrap().quiet);
+    }
+}
@@ -0,0 +1,24 @@
+use arboard::Clipboard;
+
+/// Copies `text` to the system clipboard.
+///
+/// On X11 and Wayland the clipboard is served by the process that set it, so
+/// the contents vanish when we exit. We keep serving for a short while to give
+/// clipboard managers time to take a copy.
+pub fn copy(text: &str) -> Result<(), arboard::Error> {
+    let mut clipboard = Clipboard::new()?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use arboard::SetExtLinux;
+        use std::time::{Duration, Instant};
+
+        clipboard
+            .set()
+            .wait_until(Instant::now() + Duration::from_secs(2))
+            .text(text)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    clipboard.set_text(text)
+}
@@ -1,49 +1,289 @@
+use directories::ProjectDirs;
 use serde::Deserialize;
+use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
+/// Config file extensions, in the order they're looked for.
+const CANDIDATES: &[&str] = &["yaml", "yml", "toml", "json"];
+
+// Unknown keys are rejected so a misspelt setting is reported rather than ignored
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub ignore: Option<Vec<String>>,
+    /// Patterns to aggregate when none are given on the command line
+    pub include: Option<Vec<String>>,
+    /// Default output format, overridden by `--format`
+    pub format: Option<String>,
+    /// Line written between files in plain output, overridden by `--separator`
+    pub separator: Option<String>,
+    /// Plain-output file header, overridden by `--header-template`
+    pub header_template: Option<String>,
+    /// Extra `--skip-generated` patterns; `!pattern` drops a built-in one
+    pub skip_generated: Option<Vec<String>>,
 }
 
 impl Config {
-    pub fn load() -> Self {
-        let config_path = Path::new(".agg-files");
-        if config_path.exists() {
-            if let Ok(contents) = fs::read_to_string(config_path) {
-                if let Ok(config) = serde_yaml::from_str(&contents) {
-                    return config;
-                }
+    /// Loads the global config (`~/.config/agg-files/config.yaml` on Linux)
+    /// and the project's `.agg-files` (the nearest one in the current directory
+    /// or its parents), then merges them. Ignore lists from both
+    /// are applied; for any other setting (such as `include`) the local file wins.
+    /// Either file may also be TOML or JSON (`config.toml`, `.agg-files.json`, ...);
+    /// the first one found in `CANDIDATES` order is used.
+    ///
+    /// An `explicit` path (from `--config` or `AGG_FILES_CONFIG`) is read in
+    /// place of `.agg-files`, without searching for one.
+    ///
+    /// It's an error if a config file that's found can't be read, isn't valid
+    /// for its format, or has a setting that doesn't exist or holds the wrong
+    /// type; the message names the file and, where the parser knows it, the line.
+    pub fn load(explicit: Option<&Path>) -> Result<Self, String> {
+        let global = match ProjectDirs::from("com", "seth4242", "agg-files") {
+            Some(dirs) => Self::read_first(dirs.config_dir(), "config", true)?.unwrap_or_default(),
+            None => Self::default(),
+        };
+        let local = match explicit {
+            Some(path) => Self::read(path)?,
+            None => {
+                let cwd = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+                Self::find_local(&cwd)?.unwrap_or_default()
+            }
+        };
+
+        Ok(global.merge(local))
+    }
+
+    /// Looks for `.agg-files` from `start` upwards, the way git finds `.git`.
+    /// The search stops at the repository root (a directory containing `.git`)
+    /// or the filesystem root.
+    fn find_local(start: &Path) -> Result<Option<Self>, String> {
+        for dir in start.ancestors() {
+            if let Some(config) = Self::read_first(dir, ".agg-files", false)? {
+                return Ok(Some(config));
+            }
+            if dir.join(".git").exists() {
+                break;
             }
         }
-        // Return default config if file doesn't exist or can't be parsed
-        Self { ignore: None }
+        Ok(None)
     }
 
-    pub fn should_ignore(&self, path: &str) -> bool {
-        if let Some(ignore_patterns) = &self.ignore {
-            for pattern in ignore_patterns {
-                let regex = glob_to_regex(pattern);
-                if regex.is_match(path) {
-                    return true;
-                }
+    /// Reads the first existing config named `stem` in `dir`. The extensionless
+    /// `.agg-files` is YAML, as it always has been.
+    fn read_first(dir: &Path, stem: &str, needs_extension: bool) -> Result<Option<Self>, String> {
+        let mut candidates: Vec<PathBuf> = CANDIDATES
+            .iter()
+            .map(|ext| dir.join(format!("{}.{}", stem, ext)))
+            .collect();
+        if !needs_extension {
+            candidates.insert(0, dir.join(stem));
+        }
+
+        match candidates.into_iter().find(|path| path.is_file()) {
+            Some(path) => Self::read(&path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config file {}: {}", path.display(), e))?;
+        let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string()),
+            // An empty file is no settings, not a syntax error
+            Some("json") if contents.trim().is_empty() => Ok(Self::default()),
+            Some("json") => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+            _ => serde_yaml::from_str::<Option<Self>>(&contents)
+                .map(Option::unwrap_or_default)
+                .map_err(|e| e.to_string()),
+        };
+        parsed.map_err(|e| format!("Invalid config file {}: {}", path.display(), e.trim_end()))
+    }
+
+    fn merge(self, local: Config) -> Self {
+        let ignore = match (self.ignore, local.ignore) {
+            (Some(mut global), Some(local)) => {
+                global.extend(local);
+                Some(global)
+            }
+            (global, local) => local.or(global),
+        };
+        let skip_generated = match (self.skip_generated, local.skip_generated) {
+            (Some(mut global), Some(local)) => {
+                global.extend(local);
+                Some(global)
             }
+            (global, local) => local.or(global),
+        };
+
+        Self {
+            ignore,
+            include: local.include.or(self.include),
+            format: local.format.or(self.format),
+            separator: local.separator.or(self.separator),
+            header_template: local.header_template.or(self.header_template),
+            skip_generated,
         }
-        false
     }
 }
 
-fn glob_to_regex(pattern: &str) -> regex::Regex {
-    let cleaned_pattern = pattern
-        .replace(".", "\\.")
-        .replace("*", ".*")
-        .replace("/", "\\/");
-    
-    let regex_str = cleaned_pattern
-        .trim_start_matches("./")
-        .to_string();
-    
-    regex::Regex::new(&format!("^.*{}.*$", regex_str)).unwrap()
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("agg-files-config-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn ignoring(patterns: &[&str]) -> Config {
+        Config {
+            ignore: Some(patterns.iter().map(|pattern| pattern.to_string()).collect()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn global_ignores_apply_without_a_local_file() {
+        let dir = temp_dir("global");
+        fs::write(dir.join("config.yaml"), "ignore:\n  - '*.log'\n").unwrap();
+        let global = Config::read_first(&dir, "config", true).unwrap().unwrap();
+        let merged = global.merge(Config::default());
+        assert_eq!(merged.ignore, Some(vec!["*.log".to_string()]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
+    #[test]
+    fn ignore_lists_are_merged_and_local_settings_win() {
+        let global = Config {
+            include: Some(vec!["*.md".to_string()]),
+            format: Some("markdown".to_string()),
+            ..ignoring(&["*.log"])
+        };
+        let local = Config {
+            include: Some(vec!["*.rs".to_string()]),
+            ..ignoring(&["target"])
+        };
+        let merged = global.merge(local);
+        assert_eq!(merged.ignore, Some(vec!["*.log".to_string(), "target".to_string()]));
+        assert_eq!(merged.include, Some(vec!["*.rs".to_string()]));
+        assert_eq!(merged.format.as_deref(), Some("markdown"));
+    }
+
+    #[test]
+    fn every_format_reads_the_same_settings() {
+        let dir = temp_dir("formats");
+        let files = [
+            ("config.yaml", "ignore:\n  - target\ninclude: ['*.rs']\nformat: markdown\n"),
+            ("config.toml", "ignore = [\"target\"]\ninclude = [\"*.rs\"]\nformat = \"markdown\"\n"),
+            ("config.json", r#"{"ignore": ["target"], "include": ["*.rs"], "format": "markdown"}"#),
+            (".agg-files", "ignore: [target]\ninclude: ['*.rs']\nformat: markdown\n"),
+        ];
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            let config = Config::read(&path).unwrap();
+            assert_eq!(config.ignore, Some(vec!["target".to_string()]), "{}", name);
+            assert_eq!(config.include, Some(vec!["*.rs".to_string()]), "{}", name);
+            assert_eq!(config.format.as_deref(), Some("markdown"), "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn yaml_comes_first_among_candidates() {
+        let dir = temp_dir("candidates");
+        fs::write(dir.join("config.toml"), "format = \"plain\"\n").unwrap();
+        fs::write(dir.join("config.yml"), "format: xml\n").unwrap();
+        let config = Config::read_first(&dir, "config", true).unwrap().unwrap();
+        assert_eq!(config.format.as_deref(), Some("xml"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_files_have_no_settings() {
+        let dir = temp_dir("empty");
+        for name in ["config.yaml", "config.toml", "config.json"] {
+            fs::write(dir.join(name), "").unwrap();
+            assert!(Config::read(&dir.join(name)).unwrap().ignore.is_none(), "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_global_file_needs_an_extension() {
+        let dir = temp_dir("extension");
+        fs::write(dir.join("config"), "ignore: ['a']\n").unwrap();
+        assert!(Config::read_first(&dir, "config", true).unwrap().is_none());
+        assert!(Config::read_first(&dir, "config", false).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_local_file_is_found_in_a_parent_directory() {
+        let dir = temp_dir("parents");
+        let nested = dir.join("a/b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir(dir.join(".git")).unwrap();
+        fs::write(dir.join(".agg-files"), "include: ['*.rs']\n").unwrap();
+        let found = Config::find_local(&nested).unwrap().unwrap();
+        assert_eq!(found.include, Some(vec!["*.rs".to_string()]));
+
+        // The nearest file wins
+        fs::write(dir.join("a/.agg-files.toml"), "include = ['*.md']\n").unwrap();
+        let found = Config::find_local(&nested).unwrap().unwrap();
+        assert_eq!(found.include, Some(vec!["*.md".to_string()]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_search_stops_at_the_repository_root() {
+        let dir = temp_dir("repo-root");
+        let repo = dir.join("repo");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::create_dir_all(repo.join("src")).unwrap();
+        fs::write(dir.join(".agg-files"), "include: ['*.rs']\n").unwrap();
+        assert!(Config::find_local(&repo.join("src")).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn an_explicit_path_replaces_the_search() {
+        let dir = temp_dir("explicit");
+        let path = dir.join("team-profile.toml");
+        fs::write(&path, "include = ['*.go']\n").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.include, Some(vec!["*.go".to_string()]));
+
+        // A missing file is an error rather than a fallback to `.agg-files`
+        let missing = dir.join("missing.yaml");
+        let error = Config::load(Some(&missing)).err().unwrap();
+        assert!(error.starts_with(&format!("Cannot read config file {}", missing.display())), "{}", error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn malformed_files_name_the_file_and_the_problem() {
+        let dir = temp_dir("malformed");
+        let cases = [
+            (".agg-files", "ignore:\n  - target\ninclde: ['*.rs']\n", "unknown field `inclde`, expected one of `ignore`, `include`"),
+            ("wrong-type.yaml", "ignore: target\n", "ignore: invalid type: string \"target\", expected a sequence at line 1"),
+            ("syntax.yaml", "ignore: [target\n", "did not find expected ',' or ']' at line 2 column 1"),
+            ("config.toml", "ignore = [\"target\"\n", "TOML parse error at line 1, column 19"),
+            ("config.json", "{\"format\": 1}", "invalid type: integer `1`, expected a string at line 1 column 12"),
+        ];
+        for (name, contents, problem) in cases {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            let error = Config::read(&path).err().unwrap();
+            let expected = format!("Invalid config file {}: ", path.display());
+            assert!(error.starts_with(&expected) && error.contains(problem), "{}", error);
+        }
+        // The search reports a bad file rather than skipping it
+        assert!(Config::find_local(&dir).err().unwrap().contains("unknown field `inclde`"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+use similar::TextDiff;
+
+/// Prefixes every line with its right-aligned line number, counting from
+/// `first`. The column width comes from the last line number so the gutter
+/// stays aligned, and original line endings are preserved.
+pub fn number_lines(contents: &str, first: usize) -> String {
+    let line_count = contents.split_inclusive('\n').count();
+    let width = gutter_width(contents, first) - NUMBER_SEPARATOR.len();
+
+    let mut numbered = String::with_capacity(contents.len() + line_count * (width + 3));
+    for (index, line) in contents.split_inclusive('\n').enumerate() {
+        numbered.push_str(&format!("{:>width$}{}{}", first + index, NUMBER_SEPARATOR, line, width = width));
+    }
+    numbered
+}
+
+/// Between a line number and the line
+const NUMBER_SEPARATOR: &str = " | ";
+
+/// How many characters `number_lines` puts in front of each line.
+pub fn gutter_width(contents: &str, first: usize) -> usize {
+    let line_count = contents.split_inclusive('\n').count();
+    (first + line_count.saturating_sub(1)).to_string().len() + NUMBER_SEPARATOR.len()
+}
+
+/// Starts each continuation row of a wrapped line
+const WRAP_MARKER: &str = "↪ ";
+
+/// Hard-wraps lines longer than `cols` characters. The first `gutter`
+/// characters of each line (the `-n` line number) stay on the first row, and
+/// continuation rows are indented past them and start with `↪ `, so the
+/// line numbers stay aligned and unique. Widths count characters, so a tab
+/// counts as one. Lines of exactly `cols` characters are left alone.
+pub fn wrap_lines(contents: &str, cols: usize, gutter: usize) -> String {
+    let row = cols.saturating_sub(gutter + WRAP_MARKER.chars().count()).max(1);
+
+    let mut wrapped = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\n', '\r']);
+        if body.chars().count() <= cols {
+            wrapped.push_str(line);
+            continue;
+        }
+        let ending = &line[body.len()..];
+        let newline = if ending == "\r\n" { "\r\n" } else { "\n" };
+
+        let mut chars = body.chars();
+        wrapped.extend(chars.by_ref().take(cols.max(gutter + 1)));
+        let rest: Vec<char> = chars.collect();
+        for chunk in rest.chunks(row) {
+            wrapped.push_str(newline);
+            wrapped.push_str(&" ".repeat(gutter));
+            wrapped.push_str(WRAP_MARKER);
+            wrapped.extend(chunk);
+        }
+        wrapped.push_str(ending);
+    }
+    wrapped
+}
+
+/// Converts CRLF and lone CR line endings to LF.
+pub fn normalize_newlines(contents: &str) -> String {
+    contents.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// A unified diff from `old` to `new` with three lines of context. File
+/// names get git's `a/` and `b/` prefixes, and a side that doesn't exist
+/// (an added or removed file) is `/dev/null`.
+pub fn unified_diff(old: Option<&str>, new: Option<&str>, name: &str) -> String {
+    let old_name = old.map_or_else(|| "/dev/null".to_string(), |_| format!("a/{}", name));
+    let new_name = new.map_or_else(|| "/dev/null".to_string(), |_| format!("b/{}", name));
+    TextDiff::from_lines(old.unwrap_or(""), new.unwrap_or(""))
+        .unified_diff()
+        .context_radius(3)
+        .header(&old_name, &new_name)
+        .to_string()
+}
+
+/// The part of a file selected by a `path:start-end` pattern.
+pub struct LineSelection {
+    pub text: String,
+    /// The inclusive range actually emitted, or `None` if it fell past the end
+    pub range: Option<(usize, usize)>,
+    /// Set when the requested range had to be reversed or clamped
+    pub adjusted: bool,
+}
+
+/// Fits a requested 1-based inclusive range to a file of `line_count` lines:
+/// a reversed range is swapped and one running past the end is cut short.
+/// Returns the range to emit (`None` if it starts past the end) and whether
+/// it had to be changed.
+pub fn clamp_range(start: usize, end: usize, line_count: usize) -> (Option<(usize, usize)>, bool) {
+    let (start, end, mut adjusted) = if start > end {
+        (end, start, true)
+    } else {
+        (start, end, false)
+    };
+    let start = if start == 0 {
+        adjusted = true;
+        1
+    } else {
+        start
+    };
+
+    if start > line_count {
+        return (None, true);
+    }
+    if end > line_count {
+        adjusted = true;
+    }
+    (Some((start, end.min(line_count))), adjusted)
+}
+
+/// Keeps lines `start..=end` (1-based) of `contents`, clamped as in `clamp_range`.
+pub fn select_lines(contents: &str, start: usize, end: usize) -> LineSelection {
+    let line_count = contents.split_inclusive('\n').count();
+    let (range, adjusted) = clamp_range(start, end, line_count);
+    let text = match range {
+        Some((start, end)) => contents
+            .split_inclusive('\n')
+            .skip(start - 1)
+            .take(end - start + 1)
+            .collect(),
+        None => String::new(),
+    };
+    LineSelection { text, range, adjusted }
+}
+
+/// Keeps the first `head` and/or last `tail` lines, replacing the rest with
+/// a `... N lines omitted ...` marker. Files short enough to fit are kept whole.
+pub fn truncate_lines(contents: &str, head: Option<usize>, tail: Option<usize>) -> String {
+    let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+    let head = head.unwrap_or(0);
+    let tail = tail.unwrap_or(0);
+    if head + tail >= lines.len() {
+        return contents.to_string();
+    }
+
+    let omitted = lines.len() - head - tail;
+    let mut truncated: String = lines[..head].concat();
+    if !truncated.is_empty() && !truncated.ends_with('\n') {
+        truncated.push('\n');
+    }
+    truncated.push_str(&format!("... {} lines omitted ...\n", omitted));
+    truncated.push_str(&lines[lines.len() - tail..].concat());
+    truncated
+}
+
+/// Drops lines that hold only a comment. This is a line-based heuristic: a
+/// line is removed only when its first non-blank text is `line_marker` (or,
+/// with `block`, when it opens a block comment whose closing delimiter ends
+/// a line), so code with a trailing comment and comment markers inside
+/// strings are left alone. Shebang lines are kept.
+pub fn strip_comments(contents: &str, line_marker: Option<&str>, block: Option<(&str, &str)>) -> String {
+    let mut stripped = String::with_capacity(contents.len());
+    let mut in_block = false;
+
+    for line in contents.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if in_block {
+            if let Some((_, close)) = block {
+                if trimmed.contains(close) {
+                    in_block = false;
+                    // Keep any code that follows the end of the comment
+                    if !trimmed.ends_with(close) {
+                        stripped.push_str(line);
+                    }
+                }
+            }
+            continue;
+        }
+
+        if let Some((open, close)) = block {
+            if let Some(rest) = trimmed.strip_prefix(open) {
+                match rest.find(close) {
+                    Some(_) if rest.ends_with(close) => continue,
+                    Some(_) => {}
+                    None => {
+                        in_block = true;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let is_comment = line_marker.is_some_and(|marker| trimmed.starts_with(marker) && !trimmed.starts_with("#!"));
+        if !is_comment {
+            stripped.push_str(line);
+        }
+    }
+    stripped
+}
+
+/// Drops lines that are empty or whitespace only.
+pub fn strip_blank_lines(contents: &str) -> String {
+    contents
+        .split_inclusive('\n')
+        .filter(|line| !line.trim().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIVE: &str = "one\ntwo\nthree\nfour\nfive\n";
+
+    #[test]
+    fn line_ranges_are_inclusive() {
+        let selection = select_lines(FIVE, 2, 4);
+        assert_eq!(selection.text, "two\nthree\nfour\n");
+        assert_eq!(selection.range, Some((2, 4)));
+        assert!(!selection.adjusted);
+        assert_eq!(select_lines(FIVE, 3, 3).text, "three\n");
+        assert_eq!(select_lines(FIVE, 1, 5).text, FIVE);
+    }
+
+    #[test]
+    fn line_ranges_are_clamped_and_swapped() {
+        let past_end = select_lines(FIVE, 4, 99);
+        assert_eq!(past_end.text, "four\nfive\n");
+        assert_eq!(past_end.range, Some((4, 5)));
+        assert!(past_end.adjusted);
+
+        let reversed = select_lines(FIVE, 3, 1);
+        assert_eq!(reversed.text, "one\ntwo\nthree\n");
+        assert!(reversed.adjusted);
+
+        assert_eq!(clamp_range(0, 2, 5), (Some((1, 2)), true));
+        assert_eq!(clamp_range(6, 9, 5), (None, true));
+        assert_eq!(select_lines(FIVE, 6, 9).text, "");
+        assert_eq!(select_lines("no newline", 1, 1).text, "no newline");
+    }
+
+    #[test]
+    fn numbering_starts_at_the_range() {
+        assert_eq!(number_lines("a\nb\n", 9), " 9 | a\n10 | b\n");
+        assert_eq!(gutter_width("a\nb\n", 9), 5);
+        assert_eq!(number_lines("a\r\nb", 1), "1 | a\r\n2 | b");
+    }
+
+    #[test]
+    fn head_and_tail_keep_the_ends() {
+        assert_eq!(truncate_lines(FIVE, Some(2), None), "one\ntwo\n... 3 lines omitted ...\n");
+        assert_eq!(truncate_lines(FIVE, None, Some(1)), "... 4 lines omitted ...\nfive\n");
+        assert_eq!(truncate_lines(FIVE, Some(1), Some(2)), "one\n... 2 lines omitted ...\nfour\nfive\n");
+    }
+
+    #[test]
+    fn short_files_are_kept_whole() {
+        assert_eq!(truncate_lines(FIVE, Some(3), Some(2)), FIVE);
+        assert_eq!(truncate_lines(FIVE, Some(10), None), FIVE);
+        assert_eq!(truncate_lines("a\nb\nc", Some(1), Some(1)), "a\n... 1 lines omitted ...\nc");
+    }
+
+    #[test]
+    fn rust_comment_lines_are_stripped() {
+        let rust = "\
+//! Crate docs
+/* A block
+   comment */
+fn main() { // trailing comments stay
+    let url = \"http://example.com\";
+    // gone
+    /* one line */
+    /* opens */ let x = 1;
+}
+";
+        let expected = "\
+fn main() { // trailing comments stay
+    let url = \"http://example.com\";
+    /* opens */ let x = 1;
+}
+";
+        assert_eq!(strip_comments(rust, Some("//"), Some(("/*", "*/"))), expected);
+        // Without block delimiters only line comments go
+        assert!(strip_comments(rust, Some("//"), None).contains("/* A block"));
+    }
+
+    #[test]
+    fn python_comment_lines_are_stripped() {
+        let python = "#!/usr/bin/env python3\n# comment\nimport os  # trailing\n    # indented\nprint('#')\n";
+        assert_eq!(strip_comments(python, Some("#"), None), "#!/usr/bin/env python3\nimport os  # trailing\nprint('#')\n");
+    }
+
+    #[test]
+    fn blank_lines_are_stripped() {
+        assert_eq!(strip_blank_lines("a\n\n  \n\tb\n\r\n"), "a\n\tb\n");
+    }
+
+    #[test]
+    fn crlf_and_lone_cr_become_lf() {
+        assert_eq!(normalize_newlines("a\r\nb\rc\n\r\n"), "a\nb\nc\n\n");
+        assert_eq!(normalize_newlines("no endings"), "no endings");
+    }
+
+    #[test]
+    fn lines_wrap_only_past_the_limit() {
+        assert_eq!(wrap_lines("abcde\nab\n", 5, 0), "abcde\nab\n");
+        assert_eq!(wrap_lines("abcdef\n", 5, 0), "abcde\n↪ f\n");
+        assert_eq!(wrap_lines("abcdefghijk", 5, 0), "abcde\n↪ fgh\n↪ ijk");
+        assert_eq!(wrap_lines("abcdef\r\n", 5, 0), "abcde\r\n↪ f\r\n");
+        // Multi-byte characters count once
+        assert_eq!(wrap_lines("ééééé\n", 5, 0), "ééééé\n");
+    }
+
+    #[test]
+    fn wrapped_rows_stay_clear_of_the_gutter() {
+        let numbered = number_lines("abcdefgh\nabcd\n", 1);
+        let gutter = gutter_width("abcdefgh\nabcd\n", 1);
+        assert_eq!(wrap_lines(&numbered, 8, gutter), "1 | abcd\n    ↪ ef\n    ↪ gh\n2 | abcd\n");
+    }
+}
@@ -1,185 +1,3488 @@
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, IsTerminal};
+use std::path::{Component, Path, PathBuf};
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use chrono::{SecondsFormat, Utc};
+use encoding_rs::{UTF_16BE, UTF_16LE};
+use owo_colors::OwoColorize;
+use rayon::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
-use crate::cli::CliArgs;
+use crate::archive::Archive;
+use crate::binary::{is_binary_file, looks_binary, starts_as_utf8};
+use crate::cli::{CliArgs, OutputFormat, SortOrder};
+use crate::git;
+use crate::gitattributes::ExportIgnore;
 use crate::gitignore_helper::GitignoreHelper;
-use crate::pattern_matcher::PatternMatcher;
+use crate::header::{HeaderFields, HeaderTemplate, TemplateSet, COMMENT_TEMPLATE, DEFAULT_TEMPLATE};
+use crate::pattern_matcher::{match_subject, PatternMatcher};
+use crate::progress::Progress;
 use crate::config::Config;
+use crate::content::{
+    clamp_range, gutter_width, normalize_newlines, number_lines, select_lines, strip_blank_lines, strip_comments,
+    truncate_lines, unified_diff, wrap_lines,
+};
+use crate::language::{comment_syntax_for_path, group_for_path, language_for_path, strip_syntax_for_path};
+use crate::output::Output;
+use crate::tokenizer::estimate_tokens;
+use crate::tree::render_tree;
+
+/// Files larger than this are streamed rather than read into memory, when
+/// the output options allow it (see `should_stream`).
+const STREAM_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// Written between files in plain output unless `--separator` or the config says otherwise.
+const DEFAULT_SEPARATOR: &str = "\n=====================\n";
+
+/// A file selected for aggregation, as returned by [`FileProcessor::aggregate`].
+#[derive(Debug, Clone)]
+pub struct AggregatedFile {
+    /// The path as found under the working directory
+    pub path: PathBuf,
+    /// Size on disk in bytes
+    pub size: u64,
+    /// The text that would be emitted, or `None` when the file wasn't read:
+    /// `--files-only`, binary, over `--max-file-size`, or unreadable
+    pub content: Option<String>,
+}
+
+/// Files `--skip-generated` leaves out: lock files, minified bundles, source
+/// maps and generated code. Matched like config ignore patterns.
+const GENERATED_FILES: &[&str] = &[
+    "Cargo.lock",
+    "package-lock.json",
+    "npm-shrinkwrap.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "bun.lockb",
+    "Gemfile.lock",
+    "composer.lock",
+    "poetry.lock",
+    "Pipfile.lock",
+    "uv.lock",
+    "go.sum",
+    "flake.lock",
+    "mix.lock",
+    "pubspec.lock",
+    "Podfile.lock",
+    "packages.lock.json",
+    "*.min.js",
+    "*.min.css",
+    "*.map",
+    "*.pb.go",
+    "*_pb2.py",
+    "*.g.dart",
+    "*.generated.*",
+];
+
+/// One entry of the `--format json` array. Content is decoded lossily, so any
+/// invalid UTF-8 sequences are replaced with U+FFFD rather than failing the file.
+#[derive(Serialize)]
+struct JsonFile {
+    path: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+/// One entry of the `--manifest` file: what was emitted, without the content.
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    lines: usize,
+    sha256: String,
+}
+
+/// Names handed out by `--flatten`.
+#[derive(Default)]
+struct FlatNames {
+    assigned: HashMap<PathBuf, String>,
+    /// How many files share each base name so far
+    counts: HashMap<String, usize>,
+}
+
+/// Running totals reported by `--stats` and `--count-tokens`.
+#[derive(Default)]
+struct Stats {
+    files: usize,
+    bytes: u64,
+    tokens: usize,
+    skipped_binary: usize,
+    skipped_too_large: usize,
+    errors: usize,
+    /// Emitted files and bytes per lowercased extension (`""` for none)
+    by_extension: HashMap<String, (usize, u64)>,
+}
 
 pub struct FileProcessor {
     args: CliArgs,
-    gitignore: Option<ignore::gitignore::Gitignore>,
+    gitignore: GitignoreHelper,
+    /// `export-ignore` attributes, with `--respect-export-ignore`
+    export_ignore: Option<ExportIgnore>,
     pattern_matcher: PatternMatcher,
+    /// Compiled `--exclude` globs, kept with their source for `--verbose`
+    excludes: Vec<(String, Regex)>,
+    /// Compiled `--exclude-dir` names, matched against directory names (or
+    /// relative paths, for those with a `/`)
+    exclude_dirs: Vec<(String, Regex)>,
+    config_ignores: Vec<(String, Regex)>,
+    /// `--skip-generated` patterns; empty without the flag
+    generated: Vec<(String, Regex)>,
     working_dir: PathBuf,
-    config: Config,
+    relative_to: Option<PathBuf>,
+    output_path: Option<PathBuf>,
+    /// Directory and base name of the `--split` chunks, which are skipped too
+    chunk_base: Option<(PathBuf, String)>,
+    /// Canonical `--output-dir`, whose contents are never aggregated
+    output_dir: Option<PathBuf>,
+    /// The `--archive` being read in place of the filesystem
+    archive: Option<Archive>,
+    /// For `--diff`, a processor with the same options over the other directory
+    diff_base: Option<Box<FileProcessor>>,
+    /// Directories walked into and those holding a file, for `--emit-empty-dirs`
+    walked_dirs: RefCell<BTreeSet<PathBuf>>,
+    occupied_dirs: RefCell<HashSet<PathBuf>>,
+    /// How each collected file differs from its `--diff` counterpart
+    diff_changes: RefCell<HashMap<PathBuf, Change>>,
+    /// The other `--diff` directory (canonical) when it lies inside this
+    /// one, so its files aren't collected on both sides
+    other_side: Option<PathBuf>,
+    header: HeaderTemplate,
+    /// The `--template-dir` templates, used in place of `header` and the separator
+    templates: Option<TemplateSet>,
+    /// Contents of the `--prepend` and `--append` files
+    prepend: Option<String>,
+    append: Option<String>,
+    /// Whether headers and separators are colored
+    color: bool,
+    progress: Progress,
+    output: RefCell<Output>,
+    json_files: RefCell<Vec<JsonFile>>,
+    manifest: RefCell<Vec<ManifestEntry>>,
+    /// Path and digest of each file the output already holds, with `--append-output`
+    appended: HashSet<(String, String)>,
+    /// Line ranges from `path:start-end` patterns, by collected path
+    line_ranges: RefCell<HashMap<PathBuf, (usize, usize)>>,
+    /// Non-UTF-8 paths already warned about
+    warned_paths: RefCell<HashSet<PathBuf>>,
+    /// Files already reported as skipped by `--skip-generated`
+    reported_generated: RefCell<HashSet<PathBuf>>,
+    flat_names: RefCell<FlatNames>,
+    /// `--toc` entries: each emitted file and where its header starts in the output
+    toc: RefCell<Vec<(String, usize)>>,
+    stats: RefCell<Stats>,
 }
 
 impl FileProcessor {
-    pub fn new(args: CliArgs, working_dir: PathBuf) -> Self {
-        let gitignore = if !args.ignore_gitignore {
-            GitignoreHelper::build()
+    pub fn new(
+        args: CliArgs,
+        working_dir: PathBuf,
+        output: Output,
+        config: Config,
+    ) -> Result<Self, Box<dyn Error>> {
+        let gitignore = GitignoreHelper::new(&working_dir, !args.ignore_gitignore, args.quiet);
+        let export_ignore = args.respect_export_ignore.then(|| ExportIgnore::new(&working_dir, args.quiet));
+
+        let pattern_matcher = PatternMatcher::new(args.ignore_case);
+        let excludes = args
+            .excludes
+            .iter()
+            .map(|pattern| {
+                pattern_matcher
+                    .ignore_regex(pattern)
+                    .map(|regex| (pattern.clone(), regex))
+                    .map_err(|e| format!("Invalid exclude pattern '{}': {}", pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let exclude_dirs = args
+            .exclude_dirs
+            .iter()
+            .map(|pattern| {
+                let name = pattern.trim_end_matches('/');
+                let regex = if name.contains('/') {
+                    pattern_matcher.glob_to_regex(name)
+                } else {
+                    pattern_matcher.basename_regex(name)
+                };
+                regex
+                    .map(|regex| (pattern.clone(), regex))
+                    .map_err(|e| format!("Invalid --exclude-dir '{}': {}", pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let config_ignores = config
+            .ignore
+            .iter()
+            .flatten()
+            .map(|pattern| {
+                pattern_matcher
+                    .ignore_regex(pattern)
+                    .map(|regex| (pattern.clone(), regex))
+                    .map_err(|e| format!("Invalid ignore pattern '{}' in config: {}", pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let generated = if args.skip_generated {
+            generated_patterns(config.skip_generated.as_deref().unwrap_or_default())
+                .into_iter()
+                .map(|pattern| {
+                    pattern_matcher
+                        .ignore_regex(&pattern)
+                        .map(|regex| (pattern.clone(), regex))
+                        .map_err(|e| format!("Invalid skip_generated pattern '{}' in config: {}", pattern, e))
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            Vec::new()
+        };
+
+        let relative_to = args.relative_to.as_ref().map(|dir| {
+            fs::canonicalize(dir).unwrap_or_else(|e| {
+                if !args.quiet {
+                    eprintln!("Warning: cannot resolve --relative-to {}: {}", dir.display(), e);
+                }
+                dir.clone()
+            })
+        });
+
+        // Comment headers supply their own comment marker in place of the `#`
+        let default_template = if args.comment_headers {
+            COMMENT_TEMPLATE
         } else {
-            None
+            DEFAULT_TEMPLATE
+        };
+        let header = HeaderTemplate::parse(args.header_template.as_deref().unwrap_or(default_template))?;
+        let templates = args.template_dir.as_deref().map(TemplateSet::load).transpose()?;
+        // Carried over so the manifest keeps describing the whole output file
+        let manifest = match (&args.manifest, &args.output) {
+            (Some(manifest), Some(output)) if args.append_output => read_manifest(manifest, output)?,
+            _ => Vec::new(),
+        };
+        let appended = manifest.iter().map(|entry| (entry.path.clone(), entry.sha256.clone())).collect();
+        // Anything else built from the whole output would be broken by appending to it
+        if args.append_output && matches!(args.format, OutputFormat::Json | OutputFormat::Xml) {
+            return Err("--append-output can't add to JSON or XML output".into());
+        }
+        if templates.is_some() && args.format != OutputFormat::Plain {
+            return Err("--template-dir only applies to plain output".into());
+        }
+        let prepend = args.prepend.as_deref().map(|path| read_wrapper("--prepend", path)).transpose()?;
+        let append = args.append.as_deref().map(|path| read_wrapper("--append", path)).transpose()?;
+
+        // Only color what a person is reading directly in a terminal
+        let color = !args.no_color
+            && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            && args.output.is_none()
+            && !args.clipboard
+            && !args.gzip
+            && std::io::stdout().is_terminal();
+
+        // Resolve the output file up front so it is never aggregated into itself
+        let output_path = args.output.as_ref().and_then(|p| fs::canonicalize(p).ok());
+        let output_dir = match &args.output_dir {
+            Some(dir) => Some(
+                fs::create_dir_all(dir)
+                    .and_then(|()| fs::canonicalize(dir))
+                    .map_err(|e| format!("Cannot create --output-dir {}: {}", dir.display(), e))?,
+            ),
+            None => None,
+        };
+        let chunk_base = args.output.as_ref().filter(|_| args.split.is_some()).and_then(|p| {
+            let dir = p.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            let name = p.file_name()?.to_str()?;
+            Some((fs::canonicalize(dir).ok()?, format!("{}.", name)))
+        });
+
+        let archive = match &args.archive {
+            Some(path) => Some(
+                Archive::open(path, args.max_file_size).map_err(|e| format!("Cannot read archive {}: {}", path.display(), e))?,
+            ),
+            None => None,
         };
 
-        Self {
+        let diff_base = match &args.diff {
+            Some(dir) => Some(Box::new(Self::diff_base(&args, dir, &working_dir, config.clone())?)),
+            None => None,
+        };
+        let other_side = args.diff.as_deref().and_then(|dir| nested_dir(dir, &working_dir));
+
+        let progress = Progress::new(args.progress && !args.quiet);
+
+        Ok(Self {
             args,
             gitignore,
-            pattern_matcher: PatternMatcher::new(),
+            export_ignore,
+            pattern_matcher,
+            excludes,
+            exclude_dirs,
+            config_ignores,
+            generated,
             working_dir,
-            config: Config::load(),
+            relative_to,
+            output_path,
+            chunk_base,
+            output_dir,
+            archive,
+            diff_base,
+            diff_changes: RefCell::new(HashMap::new()),
+            walked_dirs: RefCell::new(BTreeSet::new()),
+            occupied_dirs: RefCell::new(HashSet::new()),
+            other_side,
+            header,
+            templates,
+            prepend,
+            append,
+            color,
+            progress,
+            output: RefCell::new(output),
+            json_files: RefCell::new(Vec::new()),
+            manifest: RefCell::new(manifest),
+            appended,
+            line_ranges: RefCell::new(HashMap::new()),
+            warned_paths: RefCell::new(HashSet::new()),
+            reported_generated: RefCell::new(HashSet::new()),
+            toc: RefCell::new(Vec::new()),
+            flat_names: RefCell::new(FlatNames::default()),
+            stats: RefCell::new(Stats::default()),
+        })
+    }
+
+    /// A processor whose output goes nowhere, for use as a library through
+    /// `aggregate`, which hands back the files instead of writing them.
+    pub fn without_output(args: CliArgs, working_dir: PathBuf, config: Config) -> Result<Self, Box<dyn Error>> {
+        Self::new(args, working_dir, Output::discard(), config)
+    }
+
+    /// The processor for the `--diff` side: the same options, rooted at `dir`.
+    /// It's only used to collect files, so its output goes nowhere.
+    fn diff_base(args: &CliArgs, dir: &Path, working_dir: &Path, config: Config) -> Result<Self, Box<dyn Error>> {
+        if !dir.is_dir() {
+            return Err(format!("--diff {}: not a directory", dir.display()).into());
         }
+        let mut base_args = args.clone();
+        base_args.diff = None;
+        base_args.progress = false;
+        let mut base = Self::without_output(base_args, dir.to_path_buf(), config)?;
+        base.other_side = nested_dir(working_dir, dir);
+        Ok(base)
     }
 
-    pub fn process(&self) {
-        if self.args.sort_by_size {
-            self.process_with_size_sorting();
-        } else {
-            // existing processing logic
-            for pattern in &self.args.patterns {
-                let path = Path::new(pattern);
-                if path.exists() {
-                    if path.is_dir() {
-                        self.process_directory(path);
-                    } else {
-                        self.process_single_file(path);
-                    }
-                } else {
-                    self.process_glob_pattern(pattern);
-                }
+    /// The files the patterns select, in output order, with their contents
+    /// prepared as they would be emitted (line ranges, stripping, numbering
+    /// and `--head`/`--tail` applied). Nothing is written to the output.
+    pub fn aggregate(&self) -> Vec<AggregatedFile> {
+        let files = self.ordered_files();
+        let loaded_files = self.load_files(&files, !self.args.files_only, false);
+        let (files, loaded_files) = self.drop_unmatched(files, loaded_files);
+        files
+            .into_iter()
+            .zip(loaded_files)
+            .map(|((path, size), loaded)| {
+                let content = match loaded {
+                    Loaded::Contents { text, .. } => text,
+                    _ => None,
+                };
+                AggregatedFile { path, size, content }
+            })
+            .collect()
+    }
+
+    pub fn process(&self) -> std::io::Result<()> {
+        self.emit()?;
+        self.output.borrow_mut().finish()
+    }
+
+    /// Processes one of several `--url` sources into an output shared with
+    /// the others: a `# Source: <url>` banner, then its files. The output is
+    /// left unfinished for the next source; take it back with `into_output`.
+    pub fn process_source(&self, url: &str) -> std::io::Result<()> {
+        let mut output = self.output.borrow_mut();
+        output.start_entry();
+        output.write_line(&format!("# Source: {}\n", url));
+        drop(output);
+        self.emit()
+    }
+
+    pub fn into_output(self) -> Output {
+        self.output.into_inner()
+    }
+
+    /// Aggregates several `--url` sources, already fetched to `sources`, into
+    /// one output: a pass over each in turn, each under its own banner. Only the
+    /// first gets `--prepend` and only the last `--append`. Returns how many
+    /// files failed to read.
+    pub fn aggregate_sources(args: CliArgs, sources: &[PathBuf], config: Config) -> Result<usize, Box<dyn Error>> {
+        // Checked when parsing too, but a config file can still pick the format
+        if args.format.is_structured() {
+            return Err("Several --url sources can only be aggregated in plain or markdown format".into());
+        }
+        let mut output = Output::create(&args).map_err(|e| format!("Error creating output file: {}", e))?;
+
+        let mut failed = 0;
+        for (index, (url, dir)) in args.github_url.iter().zip(sources).enumerate() {
+            let mut source_args = args.clone();
+            source_args.github_url = vec![url.clone()];
+            if index > 0 {
+                source_args.prepend = None;
+            }
+            if index + 1 < sources.len() {
+                source_args.append = None;
             }
+            let processor = Self::new(source_args, dir.clone(), output, config.clone())?;
+            // Write errors are kept by the output and reported when it's finished
+            let _ = processor.process_source(url);
+            failed += processor.failed_files();
+            output = processor.into_output();
+        }
+
+        match output.finish() {
+            // A downstream reader closing the pipe early (e.g. `| head`) is not an error
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(format!("Error writing output: {}", e).into()),
+            _ => Ok(failed),
         }
     }
 
-    fn process_with_size_sorting(&self) {
-        let mut file_sizes: Vec<(PathBuf, usize)> = Vec::new();
+    fn emit(&self) -> std::io::Result<()> {
+        let files = self.ordered_files();
 
-        // Collect all matching files and their sizes
-        for pattern in &self.args.patterns {
-            let path = Path::new(pattern);
-            if path.exists() {
-                if path.is_dir() {
-                    self.collect_files_with_sizes(path, &mut file_sizes);
-                } else {
-                    if let Ok(size) = fs::metadata(path).map(|m| m.len() as usize) {
-                        file_sizes.push((path.to_path_buf(), size));
-                    }
-                }
+        if self.args.dry_run {
+            self.progress.clear();
+            self.print_dry_run(&files);
+            return Ok(());
+        }
+
+        if self.args.count_only {
+            self.print_counts(&files);
+            return Ok(());
+        }
+
+        // Size sorting lists headers only, except in JSON where content is kept
+        let include_content = self.output_dir.is_some()
+            || (!self.args.files_only
+                && (self.args.sort != SortOrder::Size || self.args.format.is_json()));
+
+        // Mirrored files are written whole, so nothing is left to stream
+        let loaded_files = self.load_files(&files, include_content, self.output_dir.is_none());
+        let (files, loaded_files) = self.drop_unmatched(files, loaded_files);
+        if self.args.context_header {
+            self.write_context_header();
+        }
+        if let Some(prepend) = self.prepend.as_ref().filter(|_| self.output_dir.is_none()) {
+            self.output.borrow_mut().write_str(prepend);
+        }
+        if let Some(header) = self.template_text(|templates| &templates.header) {
+            self.output.borrow_mut().write_str(header);
+        }
+        // Printed once reading is done, so it can't mix with the progress bar
+        if self.args.tree {
+            self.print_tree(&files, &loaded_files);
+        }
+        if self.args.format == OutputFormat::Xml && self.output_dir.is_none() {
+            self.output.borrow_mut().write_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<files>\n");
+        }
+        let mut current_group = None;
+        let mut entries = files.iter().zip(loaded_files);
+        while let Some((file, loaded)) = entries.next() {
+            let limit = if self.exceeds_total_limit(file.1, &loaded) {
+                Some("--max-total-bytes")
+            } else if self.exceeds_file_limit(&loaded) {
+                Some("--max-files")
             } else {
-                self.collect_files_from_glob(pattern, &mut file_sizes);
+                None
+            };
+            if let Some(limit) = limit {
+                // Only a file that would have been emitted trips a limit
+                let remaining = 1 + entries.filter(|(_, loaded)| loaded.will_emit()).count();
+                self.write_truncation_notice(limit, remaining);
+                break;
+            }
+            self.output.borrow_mut().start_entry();
+            if self.args.group_by_ext {
+                let group = group_for_path(&file.0);
+                if current_group.as_ref() != Some(&group) {
+                    self.write_group_header(group.as_deref(), current_group.is_none());
+                    current_group = Some(group);
+                }
+            }
+            if self.args.toc && loaded.will_emit() {
+                self.record_toc_entry(&file.0);
+            }
+            self.emit_file(&file.0, file.1, loaded);
+            if self.args.fail_fast && self.failed_files() > 0 {
+                break;
+            }
+        }
+
+        if let Some(dir) = &self.args.output_dir {
+            self.diagnostic(&format!("Wrote {} files to {}", self.stats.borrow().files, dir.display()));
+        } else {
+            match self.args.format {
+                OutputFormat::Json => self.write_json(),
+                OutputFormat::Xml => self.output.borrow_mut().write_line("</files>"),
+                _ => {}
             }
         }
+        if let Some(footer) = self.template_text(|templates| &templates.footer) {
+            let mut output = self.output.borrow_mut();
+            output.start_entry();
+            output.write_str(footer);
+        }
+        if let Some(append) = self.append.as_ref().filter(|_| self.output_dir.is_none()) {
+            let mut output = self.output.borrow_mut();
+            output.start_entry();
+            output.write_str(append);
+        }
+        if self.args.toc {
+            self.write_toc();
+        }
 
-        // Sort files by size (largest first)
-        file_sizes.sort_by(|a, b| b.1.cmp(&a.1));
+        if let Some(path) = &self.args.manifest {
+            self.write_manifest(path)?;
+        }
 
-        // Print files and their sizes
-        for (path, size) in file_sizes {
-            if let Ok(relative_path) = path.strip_prefix(&self.working_dir) {
-                println!("# File: ./{} ({} bytes)", relative_path.display(), size);
-            } else {
-                println!("# File: {} ({} bytes)", path.display(), size);
+        if self.args.count_tokens {
+            eprintln!("Approximate tokens: {}", self.stats.borrow().tokens);
+        }
+
+        if self.args.stats {
+            self.print_stats();
+        }
+        Ok(())
+    }
+
+    /// Collects the files to aggregate and puts them in output order. The
+    /// order depends only on the paths, sizes and options, never on the
+    /// filesystem's listing order, so repeated runs give identical output.
+    fn ordered_files(&self) -> Vec<(PathBuf, u64)> {
+        let mut files = self.collect_files();
+        if let Some(base) = &self.diff_base {
+            self.pair_with_base(&mut files, base);
+        }
+        if self.args.sort == SortOrder::Size {
+            // Sort files by size (largest first)
+            files.sort_by_key(|f| std::cmp::Reverse(f.1));
+        }
+        if self.args.group_by_ext {
+            files.sort_by_cached_key(|(path, _)| (group_order(group_for_path(path).as_deref()), path.clone()));
+        }
+        files
+    }
+
+    /// For `--diff`: records how each collected file relates to the other
+    /// directory, then adds the files only found there (in the order they
+    /// were collected) under the path they'd have here.
+    fn pair_with_base(&self, files: &mut Vec<(PathBuf, u64)>, base: &FileProcessor) {
+        let base_files = base.collect_files();
+        let mut unpaired: HashMap<PathBuf, &Path> = base_files
+            .iter()
+            .map(|(path, _)| (base.relative_path(path), path.as_path()))
+            .collect();
+
+        let mut changes = self.diff_changes.borrow_mut();
+        for (path, _) in files.iter() {
+            let change = match unpaired.remove(&self.relative_path(path)) {
+                Some(old) => Change::Modified(old.to_path_buf()),
+                None => Change::Added,
+            };
+            changes.insert(path.clone(), change);
+        }
+        for (old, size) in &base_files {
+            let relative = base.relative_path(old);
+            if unpaired.remove(&relative).is_some() {
+                let path = self.working_dir.join(relative);
+                changes.insert(path.clone(), Change::Removed(old.clone()));
+                files.push((path, *size));
             }
         }
     }
 
-    fn collect_files_from_glob(&self, pattern: &str, files: &mut Vec<(PathBuf, usize)>) {
-        let regex = self.pattern_matcher.glob_to_regex(pattern);
-        let walker = self.create_walker();
-        
-        for entry in walker.into_iter().filter_entry(|e| self.should_process_entry(e.path())) {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if path.is_file() && regex.is_match(path.to_str().unwrap_or("")) {
-                    if let Ok(size) = fs::metadata(path).map(|m| m.len() as usize) {
-                        files.push((path.to_path_buf(), size));
-                    }
+    /// Notes where the next file's header starts, for `--toc`. Structured
+    /// formats get no table, so nothing is recorded for them.
+    fn record_toc_entry(&self, path: &Path) {
+        if self.notices_to_stderr() {
+            return;
+        }
+        if let Some(offset) = self.output.borrow().buffered_len() {
+            let display_path = self.display_path(path);
+            self.toc.borrow_mut().push((display_path, offset));
+        }
+    }
+
+    /// Puts the `--toc` table in front of the buffered output. Each line is
+    /// `# <offset> <path>`, the offset being where that file's header starts
+    /// in the final output. All offsets are zero-padded to the same width,
+    /// which is grown until it fits the offsets shifted by the table itself.
+    fn write_toc(&self) {
+        let entries = self.toc.borrow();
+        if entries.is_empty() {
+            return;
+        }
+
+        const TITLE: &str = "# Contents (byte offset of each file's header)\n";
+        let paths_len: usize = entries.iter().map(|(path, _)| path.len()).sum();
+        let last_offset = entries.last().map_or(0, |(_, offset)| *offset);
+        let mut width = 1;
+        let toc_len = loop {
+            // "# " + offset + " " + path + "\n" per entry, then a blank line
+            let toc_len = TITLE.len() + entries.len() * (width + 4) + paths_len + 1;
+            if (last_offset + toc_len).to_string().len() <= width {
+                break toc_len;
+            }
+            width += 1;
+        };
+
+        let mut toc = String::with_capacity(toc_len);
+        toc.push_str(TITLE);
+        for (path, offset) in entries.iter() {
+            toc.push_str(&format!("# {:0width$} {}\n", offset + toc_len, path, width = width));
+        }
+        toc.push('\n');
+        self.output.borrow_mut().prepend(&toc);
+    }
+
+    /// Leaves out the files whose content didn't match `--contains`, so they
+    /// aren't counted or listed anywhere.
+    fn drop_unmatched(&self, files: Vec<(PathBuf, u64)>, loaded: Vec<Loaded>) -> (Vec<(PathBuf, u64)>, Vec<Loaded>) {
+        files
+            .into_iter()
+            .zip(loaded)
+            .filter(|((path, _), loaded)| match loaded {
+                Loaded::NoMatch => {
+                    self.log_skip(path, "content doesn't match --contains");
+                    false
+                }
+                Loaded::Unchanged => {
+                    self.log_skip(path, "same in the --diff directory");
+                    false
+                }
+                Loaded::Contents { sha256: Some(sha256), .. }
+                    if self.appended.contains(&(self.display_path(path), sha256.clone())) =>
+                {
+                    self.log_skip(path, "already in the --output file");
+                    false
+                }
+                _ => true,
+            })
+            .unzip()
+    }
+
+    /// Whether emitting a file of `size` would take the output past
+    /// `--max-total-bytes`. Files that will be skipped anyway don't count.
+    fn exceeds_total_limit(&self, size: u64, loaded: &Loaded) -> bool {
+        let Some(limit) = self.args.max_total_bytes else {
+            return false;
+        };
+        loaded.will_emit() && self.stats.borrow().bytes + size > limit
+    }
+
+    /// Whether `--max-files` files have been emitted and `loaded` would be another.
+    fn exceeds_file_limit(&self, loaded: &Loaded) -> bool {
+        let Some(limit) = self.args.max_files else {
+            return false;
+        };
+        loaded.will_emit() && self.stats.borrow().files >= limit
+    }
+
+    /// Number of files that couldn't be read, for the exit code.
+    pub fn failed_files(&self) -> usize {
+        self.stats.borrow().errors
+    }
+
+    fn count_emitted(&self, path: &Path, size: u64) {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let mut stats = self.stats.borrow_mut();
+        stats.files += 1;
+        stats.bytes += size;
+        let entry = stats.by_extension.entry(extension).or_default();
+        entry.0 += 1;
+        entry.1 += size;
+    }
+
+    fn print_stats(&self) {
+        let stats = self.stats.borrow();
+        let skipped = stats.skipped_binary + stats.skipped_too_large + stats.errors;
+        eprintln!(
+            "Processed {} files ({} bytes), skipped {} ({} binary, {} too large, {} errors)",
+            stats.files, stats.bytes, skipped, stats.skipped_binary, stats.skipped_too_large, stats.errors
+        );
+
+        // Largest share first
+        let mut breakdown: Vec<_> = stats.by_extension.iter().collect();
+        breakdown.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then_with(|| a.0.cmp(b.0)));
+        let labels: Vec<String> = breakdown
+            .iter()
+            .map(|(ext, _)| if ext.is_empty() { "(none)".to_string() } else { format!(".{}", ext) })
+            .collect();
+        let width = labels.iter().map(|label| label.chars().count()).max().unwrap_or(0);
+        for (label, (_, (files, bytes))) in labels.iter().zip(&breakdown) {
+            let share = if stats.bytes == 0 { 0.0 } else { *bytes as f64 * 100.0 / stats.bytes as f64 };
+            eprintln!(
+                "  {:<width$}  {} {}, {} bytes ({:.1}%)",
+                label,
+                files,
+                if *files == 1 { "file" } else { "files" },
+                bytes,
+                share,
+                width = width
+            );
+        }
+    }
+
+    /// Lists the files a real run would emit, plus totals, without reading them.
+    fn print_dry_run(&self, files: &[(PathBuf, u64)]) {
+        let mut output = self.output.borrow_mut();
+        let mut total_files = 0;
+        let mut total_bytes = 0;
+        for (path, size) in files {
+            if self.args.max_file_size.is_some_and(|max_size| *size > max_size) {
+                continue;
+            }
+            if self.print0() {
+                output.write_str(&format!("{}\0", self.display_path(path)));
+            } else {
+                output.write_line(&self.display_path(path));
+            }
+            total_files += 1;
+            total_bytes += size;
+        }
+
+        let mut summary = format!("{} files, {} bytes", total_files, total_bytes);
+        if self.args.emit_empty_dirs {
+            let empty_dirs = self.empty_dirs();
+            // A NUL-separated listing stays files only
+            if !self.print0() {
+                for dir in &empty_dirs {
+                    output.write_line(&format!("{}/ (empty)", self.display_path(dir)));
                 }
             }
+            summary.push_str(&format!(", {} empty directories", empty_dirs.len()));
+        }
+        if self.print0() {
+            // Keep the NUL-separated stream to paths only
+            eprintln!("{}", summary);
+        } else {
+            output.write_line(&summary);
+        }
+    }
+
+    /// Prints the layout of the files about to be emitted. In JSON, XML and with
+    /// `--print0` the tree goes to stderr so the output stays parseable.
+    fn print_tree(&self, files: &[(PathBuf, u64)], loaded_files: &[Loaded]) {
+        let paths: Vec<String> = files
+            .iter()
+            .zip(loaded_files)
+            .filter(|(_, loaded)| !matches!(loaded, Loaded::TooLarge | Loaded::TooManyLines(_)))
+            .map(|((path, _), _)| self.display_path(path))
+            .collect();
+        let empty_dirs: Vec<String> = if self.args.emit_empty_dirs {
+            self.empty_dirs().iter().map(|dir| self.display_path(dir)).collect()
+        } else {
+            Vec::new()
+        };
+        let tree = render_tree(&paths, &empty_dirs);
+
+        if self.notices_to_stderr() {
+            eprint!("{}", tree);
+        } else if self.args.format == OutputFormat::Markdown {
+            self.output.borrow_mut().write_line(&format!("```text\n{}```\n", tree));
+        } else {
+            self.output.borrow_mut().write_line(&tree);
         }
     }
 
-    fn collect_files_with_sizes(&self, dir: &Path, files: &mut Vec<(PathBuf, usize)>) {
-        let walker = WalkDir::new(dir).into_iter();
-        for entry in walker.filter_entry(|e| self.should_process_entry(e.path())) {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if path.is_file() {
-                    if let Ok(size) = fs::metadata(path).map(|m| m.len() as usize) {
-                        files.push((path.to_path_buf(), size));
+    /// Prints only the totals for the files a real run would emit. Every file
+    /// is still read, so binary and unreadable files are left out and lines
+    /// are counted after any content options (`--strip-comments`, `--head`, ...).
+    fn print_counts(&self, files: &[(PathBuf, u64)]) {
+        let mut total_files = 0;
+        let mut total_bytes = 0;
+        let mut total_lines = 0;
+        for ((path, size), loaded) in files.iter().zip(self.load_files(files, true, true)) {
+            match loaded {
+                Loaded::Contents { text, .. } => {
+                    total_files += 1;
+                    total_bytes += size;
+                    total_lines += text.map_or(0, |text| text.lines().count());
+                }
+                Loaded::Stream => match count_lines(path) {
+                    Ok(lines) => {
+                        total_files += 1;
+                        total_bytes += size;
+                        total_lines += lines;
+                    }
+                    Err(e) => {
+                        self.stats.borrow_mut().errors += 1;
+                        self.diagnostic(&format!("Error reading file: {}: {}", path.display(), e));
                     }
+                },
+                Loaded::Unreadable(e) => {
+                    self.stats.borrow_mut().errors += 1;
+                    self.diagnostic(&format!("Error reading file: {}: {}", path.display(), e));
                 }
+                Loaded::Unread
+                | Loaded::TooLarge
+                | Loaded::TooManyLines(_)
+                | Loaded::Binary
+                | Loaded::NoMatch
+                | Loaded::Unchanged => {}
             }
         }
+        self.output.borrow_mut().write_line(&format!(
+            "{} files, {} bytes, {} lines",
+            total_files, total_bytes, total_lines
+        ));
+    }
+
+    /// Formats a path for headers and listings: relative to the working
+    /// directory with a `./` prefix, or as-is when it lies outside it. With
+    /// `--relative-to`, paths are relative to that directory instead and files
+    /// outside it are shown as absolute paths.
+    fn display_path(&self, path: &Path) -> String {
+        if self.args.flatten {
+            return self.flat_name(path);
+        }
+
+        if let Some(base) = &self.relative_to {
+            let absolute = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+            return match absolute.strip_prefix(base) {
+                Ok(relative_path) => dot_relative(relative_path),
+                Err(_) => absolute.display().to_string(),
+            };
+        }
+
+        match path.strip_prefix(&self.working_dir) {
+            Ok(relative_path) => dot_relative(relative_path),
+            Err(_) => path.display().to_string(),
+        }
+    }
+
+    /// The file name alone for `--flatten`. A name already given to another
+    /// file gets a counter (`main.rs (2)`); each path keeps the name it was
+    /// first given, so the tree and headers agree.
+    fn flat_name(&self, path: &Path) -> String {
+        let mut flat_names = self.flat_names.borrow_mut();
+        if let Some(name) = flat_names.assigned.get(path) {
+            return name.clone();
+        }
+
+        let base = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        let count = flat_names.counts.entry(base.clone()).or_insert(0);
+        *count += 1;
+        let name = if *count == 1 {
+            base
+        } else {
+            format!("{} ({})", base, count)
+        };
+        flat_names.assigned.insert(path.to_path_buf(), name.clone());
+        name
+    }
+
+    fn write_json(&self) {
+        let files = self.json_files.borrow();
+        match serde_json::to_string_pretty(&*files) {
+            Ok(json) => self.output.borrow_mut().write_line(&json),
+            Err(e) => eprintln!("Error serializing JSON output: {}", e),
+        }
+    }
+
+    /// Writes the entries for the emitted files to `--manifest`, as YAML when
+    /// the path ends in `.yaml` or `.yml` and as JSON otherwise.
+    fn write_manifest(&self, path: &Path) -> std::io::Result<()> {
+        let entries = self.manifest.borrow();
+        let serialized = if is_yaml(path) {
+            serde_yaml::to_string(&*entries).map_err(|e| e.to_string())
+        } else {
+            serde_json::to_string_pretty(&*entries).map(|json| json + "\n").map_err(|e| e.to_string())
+        };
+
+        let serialized = serialized.map_err(|e| std::io::Error::other(format!("serializing manifest: {}", e)))?;
+        fs::write(path, serialized)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("manifest {}: {}", path.display(), e)))
     }
 
-    fn process_glob_pattern(&self, pattern: &str) {
-        let regex = self.pattern_matcher.glob_to_regex(pattern);
-        let walker = self.create_walker();
-        
-        for entry in walker.into_iter().filter_entry(|e| self.should_process_entry(e.path())) {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if path.is_file() && regex.is_match(path.to_str().unwrap_or("")) {
-                    self.process_single_file(path);
+    /// Resolves every pattern to the matching files and their sizes. Each
+    /// pattern's matches are sorted by path or name so the output doesn't
+    /// depend on filesystem order; size sorting is applied across all patterns.
+    fn collect_files(&self) -> Vec<(PathBuf, u64)> {
+        let mut files = Vec::new();
+        // Canonical paths already collected, so overlapping patterns emit a file once
+        let mut seen = HashSet::new();
+
+        for pattern in &self.args.patterns {
+            let mut matches = Vec::new();
+            let path = self.working_dir.join(pattern);
+            let (exists, is_dir) = match &self.archive {
+                Some(archive) => {
+                    let member = self.relative_path(&path);
+                    let is_dir = archive.is_dir(&member);
+                    (is_dir || archive.contains(&member), is_dir)
+                }
+                None => (path.exists(), path.is_dir()),
+            };
+            if exists {
+                if is_dir {
+                    self.collect_directory(&path, &mut matches);
+                } else if let Some(reason) = self.explicit_file_skip_reason(&path, false) {
+                    self.log_skip(&path, &reason);
+                } else {
+                    // Explicitly named files bypass ignore rules, but excludes still win
+                    matches.push(path);
                 }
+            } else {
+                self.collect_glob(pattern, &mut matches);
             }
+
+            let range = self.args.line_ranges.get(pattern).copied();
+            self.add_matches(matches, range, &mut files, &mut seen);
         }
-    }
 
-    fn process_directory(&self, dir: &Path) {
-        let walker = WalkDir::new(dir).into_iter();
-        for entry in walker.filter_entry(|e| self.should_process_entry(e.path())) {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if path.is_file() {
-                    self.process_single_file(path);
+        if !self.args.stdin_paths.is_empty() {
+            let mut matches = Vec::new();
+            for listed in &self.args.stdin_paths {
+                let path = self.working_dir.join(listed);
+                if path.is_dir() {
+                    self.collect_directory(&path, &mut matches);
+                } else if !path.exists() {
+                    self.diagnostic(&format!("Skipping {}: no such file", listed.display()));
+                } else if let Some(reason) = self.explicit_file_skip_reason(&path, true) {
+                    self.log_skip(&path, &reason);
+                } else {
+                    matches.push(path);
                 }
             }
+            self.add_matches(matches, None, &mut files, &mut seen);
         }
+
+        files
     }
 
-    fn create_walker(&self) -> WalkDir {
-        if self.args.recursive {
-            WalkDir::new(&self.working_dir)
+    /// Sorts one pattern's matches and appends those not already collected,
+    /// along with their sizes.
+    /// A `path:start-end` pattern's range is recorded for each file it adds.
+    fn add_matches(
+        &self,
+        mut matches: Vec<PathBuf>,
+        range: Option<(usize, usize)>,
+        files: &mut Vec<(PathBuf, u64)>,
+        seen: &mut HashSet<PathBuf>,
+    ) {
+        if self.args.sort == SortOrder::Name {
+            matches.sort_by(|a, b| a.file_name().cmp(&b.file_name()).then_with(|| a.cmp(b)));
         } else {
-            WalkDir::new(&self.working_dir).max_depth(1)
+            matches.sort();
         }
+        files.extend(matches.into_iter().filter_map(|path| {
+            // Members have nothing to canonicalize and no modification time
+            if let Some(archive) = &self.archive {
+                if !seen.insert(path.clone()) {
+                    return None;
+                }
+                let size = archive.size(&self.relative_path(&path))?;
+                return self.keep_match(path, size, range);
+            }
+
+            let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if self.args.changed_files.as_ref().is_some_and(|changed| !changed.contains(&canonical)) {
+                self.log_skip(&path, "unchanged since --changed-since");
+                return None;
+            }
+            if self.args.tracked_files.as_ref().is_some_and(|tracked| !tracked.contains(&canonical)) {
+                self.log_skip(&path, "not tracked by git");
+                return None;
+            }
+            if !seen.insert(canonical) {
+                return None;
+            }
+            let metadata = fs::metadata(&path).ok()?;
+            if let Some(since) = self.args.modified_since {
+                match metadata.modified() {
+                    Ok(modified) if modified > since => {}
+                    Ok(_) => return None,
+                    Err(_) => {
+                        self.diagnostic(&format!("Skipping {}: modification time unavailable", path.display()));
+                        return None;
+                    }
+                }
+            }
+            self.keep_match(path, metadata.len(), range)
+        }));
     }
 
-    fn should_process_entry(&self, path: &Path) -> bool {
-        // Convert path to string for config checking
-        let path_str = path.to_str().unwrap_or("");
+    /// The last of `add_matches`' checks, shared by files on disk and archive
+    /// members: `--min-file-size`. Records the file's range if it's kept.
+    fn keep_match(&self, path: PathBuf, size: u64, range: Option<(usize, usize)>) -> Option<(PathBuf, u64)> {
+        if self.args.min_file_size.is_some_and(|min_size| size < min_size) {
+            self.log_skip(&path, &format!("{} bytes is below --min-file-size", size));
+            return None;
+        }
+        if let Some(range) = range {
+            self.line_ranges.borrow_mut().insert(path.clone(), range);
+        }
+        Some((path, size))
+    }
 
-        // Check config ignore patterns
-        if self.config.should_ignore(path_str) {
-            return false;
+    /// Files named on the command line or read from stdin skip the walk, so
+    /// excludes and `--ext` are checked here instead. Files from stdin are
+    /// also subject to the config ignores; files named as patterns are not.
+    fn explicit_file_skip_reason(&self, path: &Path, apply_config: bool) -> Option<String> {
+        let path_str = &*self.match_path(path);
+        if apply_config {
+            if let Some(pattern) = matching_rule(&self.config_ignores, path_str) {
+                return Some(format!("matches config ignore pattern '{}'", pattern));
+            }
+        }
+        if let Some(pattern) = matching_rule(&self.excludes, path_str) {
+            return Some(format!("matches --exclude '{}'", pattern));
+        }
+        if !self.has_allowed_extension(path) {
+            return Some("extension not in --ext".to_string());
+        }
+        None
+    }
+
+    /// Prints a warning or per-file error to stderr unless `--quiet` is set.
+    /// Output that was explicitly asked for (`--stats`, `--verbose`, ...)
+    /// doesn't go through here.
+    fn diagnostic(&self, message: &str) {
+        if !self.args.quiet {
+            self.progress.println(message);
         }
+    }
 
-        // First check if it's a .git directory or within one
-        if path.components().any(|c| c.as_os_str() == ".git") {
-            return false;
+    /// Reports a dropped path with `--verbose`.
+    fn log_skip(&self, path: &Path, reason: &str) {
+        if self.args.verbose {
+            self.progress.println(&format!("Skipping {}: {}", path.display(), reason));
         }
+    }
 
-        // Then check gitignore if enabled
-        if let Some(gi) = &self.gitignore {
-            !gi.matched(path, path.is_dir()).is_ignore()
+    fn collect_glob(&self, pattern: &str, files: &mut Vec<PathBuf>) {
+        // Patterns with a `/` describe a path from the working directory, so
+        // they're never tested against the name alone
+        let basename = self.args.match_basename && !pattern.contains('/');
+        let regex = if basename {
+            self.pattern_matcher.basename_regex(pattern)
         } else {
-            true
+            self.pattern_matcher.glob_to_regex(pattern)
+        };
+        let regex = match regex {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.diagnostic(&format!("Invalid pattern '{}': {}", pattern, e));
+                return;
+            }
+        };
+        // A `**` can only match below the top level, so such a pattern
+        // recurses even without -r; the others follow -r
+        let recursive = self.args.recursive || pattern.contains("**");
+        let paths: Vec<PathBuf> = match &self.archive {
+            Some(archive) => self.walk_archive(archive, Path::new(""), recursive),
+            None => self.walk_files(&self.working_dir, recursive).collect(),
+        };
+
+        for path in paths {
+            // Non-UTF-8 names are matched lossily (and warned about during the walk)
+            let subject = if basename {
+                path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+            } else if self.args.abs_patterns {
+                match_subject(&std::path::absolute(&path).unwrap_or_else(|_| path.clone()))
+            } else {
+                self.match_path(&path)
+            };
+            if regex.is_match(&subject) {
+                files.push(path);
+            }
+        }
+    }
+
+    fn collect_directory(&self, dir: &Path, files: &mut Vec<PathBuf>) {
+        if let Some(archive) = &self.archive {
+            files.extend(self.walk_archive(archive, &self.relative_path(dir), true));
+            return;
+        }
+        files.extend(self.walk_files(dir, true));
+    }
+
+    /// A path relative to the working directory, without `.` components. For
+    /// `--archive` this is the member's path inside the archive.
+    fn relative_path(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.working_dir)
+            .unwrap_or(path)
+            .components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .collect()
+    }
+
+    /// The string patterns and ignore rules are matched against: the path
+    /// relative to the working directory, so where that directory lies
+    /// (a `--url` download in the cache, say) never affects what matches.
+    fn match_path(&self, path: &Path) -> String {
+        match_subject(&self.relative_path(path))
+    }
+
+    /// The archive's counterpart to `walk_files`: the members beneath `root`
+    /// (a member-relative directory, empty for the whole archive) that
+    /// pass the same depth limit and filters, as paths under the working
+    /// directory. A directory that's filtered out hides everything in it.
+    /// `.gitignore` files and the output-file checks don't apply.
+    fn walk_archive(&self, archive: &Archive, root: &Path, recursive: bool) -> Vec<PathBuf> {
+        let max_depth = self.depth_limit(recursive);
+        // Directories already rejected, so each is only reported once
+        let mut pruned: HashSet<PathBuf> = HashSet::new();
+        let mut files = Vec::new();
+
+        'members: for (member, _) in archive.files() {
+            let Ok(relative) = member.strip_prefix(root) else {
+                continue;
+            };
+            let depth = relative.components().count();
+            if depth == 0 || max_depth.is_some_and(|max_depth| depth > max_depth) {
+                continue;
+            }
+
+            let mut current = self.working_dir.join(root);
+            for (index, component) in relative.components().enumerate() {
+                current.push(component);
+                if pruned.contains(&current) {
+                    continue 'members;
+                }
+                let is_dir = index + 1 < depth;
+                let allowed_git = self.args.include_git && component.as_os_str() == ".git";
+                let reason = if !self.args.include_hidden && !allowed_git && is_hidden(&current) {
+                    Some("hidden (use --include-hidden)".to_string())
+                } else {
+                    self.rule_skip_reason(&current, is_dir)
+                };
+                if let Some(reason) = reason {
+                    self.log_skip(&current, &reason);
+                    if is_dir {
+                        pruned.insert(current);
+                    }
+                    continue 'members;
+                }
+            }
+
+            if let Some(pattern) = matching_rule(&self.generated, &self.match_path(&current)) {
+                if self.reported_generated.borrow_mut().insert(current.clone()) {
+                    self.diagnostic(&format!(
+                        "Skipping {}: looks generated (matches '{}')",
+                        current.display(),
+                        pattern
+                    ));
+                }
+                continue;
+            }
+            self.progress.found();
+            files.push(current);
         }
+        files
+    }
+
+    /// Yields the files under `root` that pass `should_process_entry`.
+    /// Symlink loops (only possible with `--follow-symlinks`) are detected by
+    /// WalkDir and reported rather than followed.
+    fn walk_files<'a>(&'a self, root: &Path, recursive: bool) -> impl Iterator<Item = PathBuf> + 'a {
+        let depth_limit = self.depth_limit(recursive);
+        self.create_walker(root, recursive)
+            .into_iter()
+            .filter_entry(|e| {
+                // `--include-git` is enough to walk into `.git` without `-H`
+                let allowed_git = self.args.include_git && e.file_name() == ".git";
+                // The walk's root was named explicitly, so it counts even if hidden
+                if e.depth() > 0 && !self.args.include_hidden && !allowed_git && is_hidden(e.path()) {
+                    self.log_skip(e.path(), "hidden (use --include-hidden)");
+                    return false;
+                }
+                self.should_process_entry(e.path())
+            })
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    if e.loop_ancestor().is_some() {
+                        self.diagnostic(&format!("Skipping symlink loop: {}", e));
+                    }
+                    None
+                }
+            })
+            .inspect(move |entry| {
+                if self.args.emit_empty_dirs {
+                    self.note_walked(entry, depth_limit);
+                }
+            })
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| {
+                self.progress.found();
+                entry.into_path()
+            })
     }
 
-    fn process_single_file(&self, path: &Path) {
-        println!("# File: {}", path.display());
-        if !self.args.files_only {
-            match fs::read_to_string(path) {
-                Ok(contents) => {
-                    println!("{}", contents);
-                    println!("\n=====================\n");
+    /// Reads all files in parallel, returning results in the same order as `files`.
+    /// Without `allow_stream`, large files are read into memory like any other
+    /// instead of being left to stream.
+    fn load_files(&self, files: &[(PathBuf, u64)], include_content: bool, allow_stream: bool) -> Vec<Loaded> {
+        let args = &self.args;
+        let progress = &self.progress;
+        progress.start_reading(files.len());
+        let line_ranges = self.line_ranges.borrow();
+        let line_ranges = &*line_ranges;
+        let changes = self.diff_changes.borrow();
+        let sources: Vec<Source> = files
+            .iter()
+            .map(|(path, _)| match (&self.archive, changes.get(path)) {
+                (Some(archive), _) => Source::Member(archive.read(&self.relative_path(path))),
+                (None, Some(change)) => Source::Diff(change, self.match_path(path)),
+                (None, None) => Source::Disk,
+            })
+            .collect();
+        let load = || {
+            files
+                .par_iter()
+                .zip(&sources)
+                .map(|((path, size), source)| {
+                    let range = line_ranges.get(path).copied();
+                    let mut notes = Vec::new();
+                    let loaded =
+                        load_file(args, path, *size, include_content, allow_stream, range, source, &mut notes);
+                    progress.read();
+                    (loaded, notes)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let loaded = match args.jobs {
+            Some(jobs) => match rayon::ThreadPoolBuilder::new().num_threads(jobs).build() {
+                Ok(pool) => pool.install(load),
+                Err(e) => {
+                    self.diagnostic(&format!("Warning: failed to create thread pool: {}", e));
+                    load()
+                }
+            },
+            None => load(),
+        };
+        // Reported from here rather than the pool, so they can't cut into the progress bar
+        let loaded = loaded
+            .into_iter()
+            .map(|(loaded, notes)| {
+                for note in notes {
+                    self.diagnostic(&note.message());
                 }
-                Err(_) => println!("Error reading file: {}", path.display()),
+                loaded
+            })
+            .collect();
+        self.progress.clear();
+        loaded
+    }
+
+    /// For `--emit-empty-dirs`: remembers the directories whose contents the walk
+    /// went through and those with a file somewhere below them.
+    fn note_walked(&self, entry: &walkdir::DirEntry, depth_limit: Option<usize>) {
+        if entry.path().is_dir() {
+            // A directory at the depth limit was never looked into
+            if entry.depth() > 0 && depth_limit.is_none_or(|limit| entry.depth() < limit) {
+                self.walked_dirs.borrow_mut().insert(entry.path().to_path_buf());
+            }
+            return;
+        }
+        let mut occupied = self.occupied_dirs.borrow_mut();
+        for dir in entry.path().ancestors().skip(1) {
+            // Its parents were marked along with it
+            if !occupied.insert(dir.to_path_buf()) {
+                break;
+            }
+        }
+    }
+
+    /// The directories `--emit-empty-dirs` reports: walked, but with no file
+    /// below them that passed the filters. Only the innermost are listed; a
+    /// directory holding nothing but empty ones shows up as their parent.
+    fn empty_dirs(&self) -> Vec<PathBuf> {
+        let occupied = self.occupied_dirs.borrow();
+        let walked = self.walked_dirs.borrow();
+        let parents: HashSet<&Path> = walked.iter().filter_map(|dir| dir.parent()).collect();
+        walked
+            .iter()
+            .filter(|dir| !occupied.contains(*dir) && !parents.contains(dir.as_path()))
+            .cloned()
+            .collect()
+    }
+
+    /// How deep a walk goes; an explicit `--max-depth` overrides the
+    /// recursive/non-recursive choice.
+    fn depth_limit(&self, recursive: bool) -> Option<usize> {
+        self.args.max_depth.or((!recursive).then_some(1))
+    }
+
+    fn create_walker(&self, root: &Path, recursive: bool) -> WalkDir {
+        let walker = WalkDir::new(root).follow_links(self.args.follow_symlinks);
+        match self.depth_limit(recursive) {
+            Some(max_depth) => walker.max_depth(max_depth),
+            None => walker,
+        }
+    }
+
+    /// Whether a change reported by the file watcher at `path` (an absolute
+    /// path under `root`, the canonical working directory) passes the same
+    /// filters a walk would apply to it and its parent directories.
+    pub fn is_relevant_change(&self, root: &Path, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+
+        let mut current = self.working_dir.clone();
+        for component in relative.components() {
+            current.push(component);
+            if self.skip_reason(&current).is_some() {
+                return false;
             }
         }
+        self.generated_rule(path).is_none()
+    }
+
+    /// Applies `--ext`, matching extensions case-insensitively.
+    fn has_allowed_extension(&self, path: &Path) -> bool {
+        if self.args.extensions.is_empty() {
+            return true;
+        }
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.args.extensions.contains(&ext.to_lowercase()))
+    }
+
+    fn should_process_entry(&self, path: &Path) -> bool {
+        // Each pattern walks the tree again, so only warn the first time
+        if path.to_str().is_none() && self.warned_paths.borrow_mut().insert(path.to_path_buf()) {
+            self.diagnostic(&format!(
+                "Warning: {} is not valid UTF-8; patterns see the invalid bytes as U+FFFD",
+                path.display()
+            ));
+        }
+
+        match self.skip_reason(path) {
+            Some(reason) => {
+                self.log_skip(path, &reason);
+                false
+            }
+            // Checked last so files ignored anyway aren't reported
+            None => match self.generated_rule(path) {
+                Some(pattern) => {
+                    if self.reported_generated.borrow_mut().insert(path.to_path_buf()) {
+                        self.diagnostic(&format!(
+                            "Skipping {}: looks generated (matches '{}')",
+                            path.display(),
+                            pattern
+                        ));
+                    }
+                    false
+                }
+                None => true,
+            },
+        }
+    }
+
+    /// The `--skip-generated` pattern a file matches, if any.
+    fn generated_rule(&self, path: &Path) -> Option<&str> {
+        if self.generated.is_empty() || path.is_dir() {
+            return None;
+        }
+        matching_rule(&self.generated, &self.match_path(path))
+    }
+
+    /// Why a walked path is filtered out, naming the rule that matched, or
+    /// `None` if it passes.
+    fn skip_reason(&self, path: &Path) -> Option<String> {
+        if let Some(reason) = self.rule_skip_reason(path, path.is_dir()) {
+            return Some(reason);
+        }
+
+        // Skip the file we're writing into
+        if let Some(output_path) = &self.output_path {
+            if fs::canonicalize(path).ok().as_ref() == Some(output_path) {
+                return Some("is the output file".to_string());
+            }
+        }
+        if self.is_output_chunk(path) {
+            return Some("is an output chunk".to_string());
+        }
+        if let Some(output_dir) = &self.output_dir {
+            if fs::canonicalize(path).is_ok_and(|path| path.starts_with(output_dir)) {
+                return Some("inside --output-dir".to_string());
+            }
+        }
+        if let Some(other_side) = &self.other_side {
+            if fs::canonicalize(path).is_ok_and(|path| path.starts_with(other_side)) {
+                return Some("inside the other --diff directory".to_string());
+            }
+        }
+
+        // Rule files are matched from the working directory, and a stray `./`
+        // (from searching `.`) would keep anchored patterns like `src/gen/**` from matching
+        let is_dir = path.is_dir();
+        let path = &self.working_dir.join(self.relative_path(path));
+        if let Some(reason) = self.export_ignore.as_ref().and_then(|attributes| attributes.ignored_by(path, is_dir)) {
+            return Some(reason);
+        }
+
+        // Then check .gitignore (unless disabled) and the other ignore files
+        self.gitignore.ignored_by(path, is_dir)
+    }
+
+    /// The part of `skip_reason` that only looks at the path itself, so it
+    /// also applies to archive members: ignores, excludes, `--ext` and `.git`.
+    fn rule_skip_reason(&self, path: &Path, is_dir: bool) -> Option<String> {
+        // Convert path to string for config checking; invalid UTF-8 becomes U+FFFD
+        let path_str = &*self.match_path(path);
+
+        // Check config ignore patterns
+        if let Some(pattern) = matching_rule(&self.config_ignores, path_str) {
+            return Some(format!("matches config ignore pattern '{}'", pattern));
+        }
+
+        // Excludes take precedence over any include pattern
+        if let Some(pattern) = matching_rule(&self.excludes, path_str) {
+            return Some(format!("matches --exclude '{}'", pattern));
+        }
+
+        // Pruned as soon as the walk reaches them, so nothing inside is even listed
+        if is_dir && !self.exclude_dirs.is_empty() {
+            let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            let rule = self.exclude_dirs.iter().find(|(pattern, regex)| {
+                let subject = if pattern.trim_end_matches('/').contains('/') { path_str } else { &name };
+                regex.is_match(subject)
+            });
+            if let Some((pattern, _)) = rule {
+                return Some(format!("matches --exclude-dir '{}'", pattern));
+            }
+        }
+
+        // Directories must pass so their contents can be checked
+        if !is_dir && !self.has_allowed_extension(path) {
+            return Some("extension not in --ext".to_string());
+        }
+
+        if !self.args.include_git && path.components().any(|c| c.as_os_str() == ".git") {
+            return Some("inside .git".to_string());
+        }
+        None
+    }
+
+    /// Whether `path` is named like one of the `--split` chunks next to `--output`.
+    fn is_output_chunk(&self, path: &Path) -> bool {
+        let Some((dir, prefix)) = &self.chunk_base else {
+            return false;
+        };
+        let numbered = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(prefix.as_str()))
+            .is_some_and(|suffix| !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()));
+        numbered
+            && path
+                .parent()
+                .map(|parent| if parent.as_os_str().is_empty() { Path::new(".") } else { parent })
+                .and_then(|parent| fs::canonicalize(parent).ok())
+                .as_ref()
+                == Some(dir)
+    }
+
+    /// Writes one loaded file in the selected output format.
+    fn emit_file(&self, path: &Path, size: u64, loaded: Loaded) {
+        let display_path = self.display_path(path);
+
+        let contents = match loaded {
+            Loaded::TooLarge => {
+                self.stats.borrow_mut().skipped_too_large += 1;
+                self.log_skip(path, &format!("{} bytes exceeds --max-file-size", size));
+                self.write_notice(&format!(
+                    "# Skipped (too large): {} ({} bytes)",
+                    display_path, size
+                ));
+                return;
+            }
+            Loaded::TooManyLines(lines) => {
+                self.stats.borrow_mut().skipped_too_large += 1;
+                self.log_skip(path, &format!("{} lines exceeds --max-lines", lines));
+                self.write_notice(&format!(
+                    "# Skipped (too many lines): {} ({} lines)",
+                    display_path, lines
+                ));
+                return;
+            }
+            Loaded::Binary => {
+                self.stats.borrow_mut().skipped_binary += 1;
+                self.log_skip(path, "binary file");
+                self.write_notice(&format!("# Binary file skipped: {}", display_path));
+                return;
+            }
+            Loaded::Unreadable(e) => {
+                self.stats.borrow_mut().errors += 1;
+                self.diagnostic(&format!("Error reading file: {}: {}", path.display(), e));
+                return;
+            }
+            Loaded::Stream => {
+                self.stream_file(path, &display_path, size);
+                return;
+            }
+            // Normally dropped before emitting, by `drop_unmatched`
+            Loaded::NoMatch | Loaded::Unchanged => return,
+            Loaded::Unread => None,
+            Loaded::Contents { text, tokens, lines, sha256, line_range } => {
+                if let Some(tokens) = tokens {
+                    self.stats.borrow_mut().tokens += tokens;
+                }
+                if let Some(sha256) = sha256.as_ref().filter(|_| self.args.manifest.is_some()) {
+                    self.manifest.borrow_mut().push(ManifestEntry {
+                        path: display_path.clone(),
+                        size,
+                        lines: lines.unwrap_or(0),
+                        sha256: sha256.clone(),
+                    });
+                }
+                Some((text, tokens, sha256, line_range))
+            }
+        };
+
+        if let Some(dir) = &self.output_dir {
+            if let Some((Some(text), ..)) = &contents {
+                self.write_mirrored(dir, path, size, text);
+            }
+            return;
+        }
+
+        self.count_emitted(path, size);
+        let (contents, tokens, sha256, line_range) = contents.unwrap_or_default();
+
+        let mut details = String::new();
+        if let Some(change) = self.diff_changes.borrow().get(path) {
+            details.push_str(match change {
+                Change::Added => " (added)",
+                Change::Removed(_) => " (removed)",
+                Change::Modified(_) => " (modified)",
+            });
+        }
+        if let Some((first, last)) = line_range {
+            details.push_str(&format!(" (lines {}-{})", first, last));
+        }
+        if self.args.sort == SortOrder::Size {
+            details.push_str(&format!(" ({} bytes)", size));
+        }
+        // Per-file token counts are shown when only paths are listed
+        if let Some(tokens) = tokens.filter(|_| contents.is_none()) {
+            details.push_str(&format!(" (~{} tokens)", tokens));
+        }
+        if let Some(sha256) = sha256.as_ref().filter(|_| self.args.hash) {
+            details.push_str(&format!(" (sha256: {})", sha256));
+        }
+
+        if self.print0() {
+            self.output.borrow_mut().write_str(&format!("{}\0", display_path));
+            return;
+        }
+
+        match self.args.format {
+            OutputFormat::Json => {
+                self.json_files.borrow_mut().push(JsonFile {
+                    path: display_path,
+                    size,
+                    tokens,
+                    sha256: sha256.filter(|_| self.args.hash),
+                    content: contents,
+                });
+            }
+            OutputFormat::JsonLines => {
+                let file = JsonFile {
+                    path: display_path,
+                    size,
+                    tokens,
+                    sha256: sha256.filter(|_| self.args.hash),
+                    content: contents,
+                };
+                // Escaped newlines keep each object on its own line
+                match serde_json::to_string(&file) {
+                    Ok(json) => self.output.borrow_mut().write_line(&json),
+                    Err(e) => eprintln!("Error serializing JSON output: {}", e),
+                }
+            }
+            OutputFormat::Markdown => {
+                let mut output = self.output.borrow_mut();
+                output.write_line(&self.paint_header(&format!("## {}{}", display_path, details)));
+                output.write_line("");
+                if let Some(contents) = contents {
+                    // Use a fence longer than any backtick run in the file so it can't close early
+                    let mut fence = String::from("```");
+                    while contents.contains(&fence) {
+                        fence.push('`');
+                    }
+                    let language = language_for_path(path).unwrap_or("");
+
+                    output.write_line(&format!("{}{}", fence, language));
+                    output.write_str(&contents);
+                    if !contents.is_empty() && !contents.ends_with('\n') {
+                        output.write_str("\n");
+                    }
+                    output.write_line(&fence);
+                    output.write_line("");
+                }
+            }
+            OutputFormat::Xml => {
+                let mut element = format!("<file path=\"{}\" size=\"{}\"", xml_attr(&display_path), size);
+                if let Some(tokens) = tokens {
+                    element.push_str(&format!(" tokens=\"{}\"", tokens));
+                }
+                if let Some(sha256) = sha256.as_ref().filter(|_| self.args.hash) {
+                    element.push_str(&format!(" sha256=\"{}\"", sha256));
+                }
+                let element = match contents {
+                    Some(contents) => format!("{}>{}</file>", element, xml_cdata(&contents)),
+                    None => format!("{}/>", element),
+                };
+                self.output.borrow_mut().write_line(&element);
+            }
+            OutputFormat::Plain => {
+                let lines = contents.as_deref().map(|contents| contents.lines().count());
+                if let Some(templates) = &self.templates {
+                    let rendered = templates.file.render(&HeaderFields {
+                        path: &display_path,
+                        size,
+                        lines,
+                        ext: path.extension().and_then(|ext| ext.to_str()).unwrap_or(""),
+                        content: contents.as_deref(),
+                    });
+                    self.output.borrow_mut().write_str(&rendered);
+                    return;
+                }
+                let header = self.plain_header(path, &display_path, size, lines, &details);
+                self.write_plain_file(&header, contents.as_deref());
+            }
+        }
+    }
+
+    /// Writes a file's processed contents to the same relative path under
+    /// `--output-dir`.
+    fn write_mirrored(&self, dir: &Path, path: &Path, size: u64, text: &str) {
+        let relative = path.strip_prefix(&self.working_dir).unwrap_or(path);
+        // Paths leading outside the working directory have nowhere to go in the mirror
+        if !relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            self.diagnostic(&format!(
+                "Skipping {}: outside the working directory, can't be mirrored",
+                path.display()
+            ));
+            return;
+        }
+
+        let target = dir.join(relative);
+        let written = target
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&target, text));
+        match written {
+            Ok(()) => self.count_emitted(path, size),
+            Err(e) => {
+                self.stats.borrow_mut().errors += 1;
+                self.diagnostic(&format!("Error writing {}: {}", target.display(), e));
+            }
+        }
+    }
+
+    /// The `--template-dir` header or footer, when there's one to write: not
+    /// for `--output-dir` or a NUL-separated path list.
+    fn template_text(&self, text: impl Fn(&TemplateSet) -> &Option<String>) -> Option<&str> {
+        if self.output_dir.is_some() || self.print0() {
+            return None;
+        }
+        self.templates.as_ref().and_then(|templates| text(templates).as_deref())
+    }
+
+    /// Renders the header template for a file, with `details` appended and
+    /// wrapped in a comment for `--comment-headers`.
+    fn plain_header(&self, path: &Path, display_path: &str, size: u64, lines: Option<usize>, details: &str) -> String {
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let header = self.header.render(&HeaderFields {
+            path: display_path,
+            size,
+            lines,
+            ext,
+            content: None,
+        });
+        if self.args.comment_headers {
+            let (open, close) = comment_syntax_for_path(path);
+            format!("{} {}{}{}", open, header, details, close)
+        } else {
+            format!("{}{}", header, details)
+        }
+    }
+
+    /// Emits a large file in plain output while reading it line by line, so
+    /// memory use doesn't grow with the file. Lines are counted in a first
+    /// pass, which line ranges, `--tail` and the `-n` gutter need.
+    fn stream_file(&self, path: &Path, display_path: &str, size: u64) {
+        let line_count = match count_lines(path) {
+            Ok(line_count) => line_count,
+            Err(e) => {
+                self.stats.borrow_mut().errors += 1;
+                self.diagnostic(&format!("Error reading file: {}: {}", path.display(), e));
+                return;
+            }
+        };
+
+        let requested = self.line_ranges.borrow().get(path).copied();
+        let range = match requested {
+            Some((start, end)) => {
+                let (range, adjusted) = clamp_range(start, end, line_count);
+                if adjusted {
+                    let note = Note::RangeAdjusted {
+                        path: path.to_path_buf(),
+                        requested: (start, end),
+                        actual: range,
+                    };
+                    self.diagnostic(&note.message());
+                }
+                range
+            }
+            None => (line_count > 0).then_some((1, line_count)),
+        };
+
+        self.count_emitted(path, size);
+
+        let mut details = String::new();
+        if let Some((first, last)) = range.filter(|_| requested.is_some()) {
+            details.push_str(&format!(" (lines {}-{})", first, last));
+        }
+        let selected = range.map_or(0, |(first, last)| last - first + 1);
+        let header = self.plain_header(path, display_path, size, Some(selected), &details);
+        self.output.borrow_mut().write_line(&self.paint_header(header.as_str()));
+
+        if let Some((first, last)) = range {
+            if let Err(e) = self.stream_lines(path, first, last) {
+                self.stats.borrow_mut().errors += 1;
+                self.diagnostic(&format!("Error reading file: {}: {}", path.display(), e));
+            }
+        }
+
+        let mut output = self.output.borrow_mut();
+        output.write_str("\n");
+        self.write_separator(&mut output);
+    }
+
+    /// Copies lines `first..=last` of a file to the output, applying `--head`,
+    /// `--tail` and `-n` the same way as for files read into memory.
+    fn stream_lines(&self, path: &Path, first: usize, last: usize) -> std::io::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut output = self.output.borrow_mut();
+
+        let selected = last - first + 1;
+        let head = self.args.head.unwrap_or(0);
+        let tail = self.args.tail.unwrap_or(0);
+        let whole = (self.args.head.is_none() && self.args.tail.is_none()) || head + tail >= selected;
+        let width = last.to_string().len();
+
+        let mut line = String::new();
+        let mut number = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || number >= last {
+                break;
+            }
+            number += 1;
+            if number < first {
+                continue;
+            }
+
+            let index = number - first;
+            if !whole && index >= head && index < selected - tail {
+                if index == head {
+                    output.write_str(&format!("... {} lines omitted ...\n", selected - head - tail));
+                }
+                continue;
+            }
+            if self.args.line_numbers {
+                output.write_str(&format!("{:>width$} | ", number, width = width));
+            }
+            output.write_str(&line);
+        }
+        Ok(())
+    }
+
+    /// Writes a file's rendered header and, when content is emitted, its
+    /// contents followed by the separator. An empty separator writes nothing at all.
+    fn write_plain_file(&self, header: &str, contents: Option<&str>) {
+        let mut output = self.output.borrow_mut();
+        output.write_line(&self.paint_header(header));
+        if let Some(contents) = contents {
+            output.write_line(contents);
+            self.write_separator(&mut output);
+        }
+    }
+
+    fn write_separator(&self, output: &mut Output) {
+        let separator = self.args.separator.as_deref().unwrap_or(DEFAULT_SEPARATOR);
+        if !separator.is_empty() {
+            output.write_line(&self.paint_separator(separator));
+        }
+    }
+
+    fn paint_header(&self, header: &str) -> String {
+        if self.color {
+            header.cyan().bold().to_string()
+        } else {
+            header.to_string()
+        }
+    }
+
+    /// Colors each line separately, so no escape code spans a newline.
+    fn paint_separator(&self, separator: &str) -> String {
+        if !self.color {
+            return separator.to_string();
+        }
+        separator
+            .split('\n')
+            .map(|line| if line.is_empty() { String::new() } else { line.dimmed().to_string() })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Whether paths are listed bare and NUL-separated. JSON and XML keep
+    /// their own structure, so `--print0` doesn't apply there.
+    fn print0(&self) -> bool {
+        self.args.print0 && !self.args.format.is_structured()
+    }
+
+    /// JSON, XML and `--print0` output must stay machine-parseable, so
+    /// anything that isn't file data goes to stderr for them.
+    fn notices_to_stderr(&self) -> bool {
+        self.args.format.is_structured()
+            || self.print0()
+            || self.output_dir.is_some()
+    }
+
+    /// Starts a `--group-by-ext` section. JSON and XML have no place for it.
+    fn write_group_header(&self, group: Option<&str>, first: bool) {
+        if self.notices_to_stderr() {
+            return;
+        }
+        let header = format!("### {} files", group.unwrap_or("Other"));
+        let mut output = self.output.borrow_mut();
+        // Path listings have no separator to set the previous group apart
+        if self.args.files_only && !first {
+            output.write_line("");
+        }
+        output.write_line(&self.paint_header(&header));
+        output.write_line("");
+    }
+
+    /// Writes the `--context-header` block: what's being aggregated, its git
+    /// branch and commit when it's in a repository, and when the output was
+    /// generated. Structured formats get it on stderr, like other notices.
+    fn write_context_header(&self) {
+        let repo = git::repo_context(&self.working_dir);
+        let name = if let Some(url) = self.args.github_url.first() {
+            url.clone()
+        } else if let Some(archive) = &self.args.archive {
+            archive.display().to_string()
+        } else {
+            let dir = match &repo {
+                Some(repo) => repo.root.clone(),
+                None => fs::canonicalize(&self.working_dir).unwrap_or_else(|_| self.working_dir.clone()),
+            };
+            dir.file_name().map_or_else(|| dir.display().to_string(), |name| name.to_string_lossy().into_owned())
+        };
+
+        let mut block = format!("# Repository: {}\n", name);
+        // A downloaded or archived copy isn't a checkout, even if it sits inside one
+        if let Some(repo) = repo.filter(|_| self.args.github_url.is_empty() && self.archive.is_none()) {
+            if let Some(branch) = repo.branch {
+                block.push_str(&format!("# Branch: {}\n", branch));
+            }
+            if let Some(commit) = repo.commit {
+                block.push_str(&format!("# Commit: {}\n", commit));
+            }
+        }
+        block.push_str(&format!("# Generated: {}\n", Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)));
+
+        if self.notices_to_stderr() {
+            eprint!("{}", block);
+        } else {
+            self.output.borrow_mut().write_line(&block);
+        }
+    }
+
+    /// Writes an inline notice into the aggregation, or to stderr when the
+    /// output has to stay machine-parseable.
+    /// Notes that `limit` cut the output short, with `remaining` files that
+    /// would otherwise have been emitted.
+    fn write_truncation_notice(&self, limit: &str, remaining: usize) {
+        self.write_notice(&format!(
+            "# Output truncated: {} reached, {} more files not emitted",
+            limit, remaining
+        ));
+    }
+
+    fn write_notice(&self, notice: &str) {
+        if self.notices_to_stderr() {
+            self.diagnostic(notice);
+        } else {
+            self.output.borrow_mut().write_line(notice);
+        }
+    }
+}
+
+/// Whether a `--manifest` path is written as YAML rather than JSON.
+fn is_yaml(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"))
+}
+
+/// Reads back the `--manifest` of an earlier run for `--append-output`. It
+/// only describes the output if that file is still there, so a missing or
+/// empty output starts both afresh. An output without its manifest is an
+/// error: nothing would say which files it already holds.
+fn read_manifest(path: &Path, output: &Path) -> Result<Vec<ManifestEntry>, String> {
+    let has_output = fs::metadata(output).is_ok_and(|metadata| metadata.len() > 0);
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) if has_output => contents,
+        Ok(_) => return Ok(Vec::new()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !has_output => return Ok(Vec::new()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!(
+                "Cannot append to {}: its manifest {} is missing, so the files it holds are unknown",
+                output.display(),
+                path.display()
+            ))
+        }
+        Err(e) => return Err(format!("Cannot read manifest {}: {}", path.display(), e)),
+    };
+    let entries = if is_yaml(path) {
+        serde_yaml::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    };
+    entries.map_err(|e| format!("Invalid manifest {}: {}", path.display(), e))
+}
+
+/// Reads a `--prepend`/`--append` file, ending it with a newline so what
+/// follows starts on a line of its own.
+fn read_wrapper(flag: &str, path: &Path) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Cannot read {} file {}: {}", flag, path.display(), e))?;
+    let mut text = String::from_utf8_lossy(&bytes).into_owned();
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// Escapes a value for a double-quoted XML attribute.
+fn xml_attr(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push_str(&format!("&#{};", c as u32)),
+            c => escaped.push(xml_char(c)),
+        }
+    }
+    escaped
+}
+
+/// Wraps text in a CDATA section. A `]]>` in the text would end the section
+/// early, so the section is closed between its `]]` and `>` and reopened.
+fn xml_cdata(text: &str) -> String {
+    let text: String = text.chars().map(xml_char).collect();
+    format!("<![CDATA[{}]]>", text.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+/// XML 1.0 can't contain most control characters even escaped, so they're
+/// replaced with U+FFFD.
+fn xml_char(c: char) -> char {
+    match c {
+        '\t' | '\n' | '\r' => c,
+        '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => char::REPLACEMENT_CHARACTER,
+        c => c,
+    }
+}
+
+/// The built-in `GENERATED_FILES` plus the config's `skip_generated` entries,
+/// minus any built-in one named there as `!pattern`.
+fn generated_patterns(extra: &[String]) -> Vec<String> {
+    let removed: HashSet<&str> = extra.iter().filter_map(|pattern| pattern.strip_prefix('!')).collect();
+    GENERATED_FILES
+        .iter()
+        .filter(|pattern| !removed.contains(*pattern))
+        .map(|pattern| pattern.to_string())
+        .chain(extra.iter().filter(|pattern| !pattern.starts_with('!')).cloned())
+        .collect()
+}
+
+/// Where a `--group-by-ext` group sorts: alphabetically by name, ignoring
+/// case and the `.` of an extension group (`.txt` sorts as `txt`), with
+/// files without an extension last.
+fn group_order(group: Option<&str>) -> (bool, String) {
+    (group.is_none(), group.unwrap_or_default().trim_start_matches('.').to_lowercase())
+}
+
+/// A path below the working directory with a `./` prefix. One that climbs
+/// out of it with `..`, or is absolute, is shown as it is.
+fn dot_relative(relative: &Path) -> String {
+    let relative: PathBuf = relative.components().skip_while(|c| matches!(c, Component::CurDir)).collect();
+    match relative.components().next() {
+        Some(Component::ParentDir | Component::RootDir | Component::Prefix(_)) => relative.display().to_string(),
+        _ => format!("./{}", relative.display()),
+    }
+}
+
+/// `inner`, canonicalized, if it lies strictly inside `outer`.
+fn nested_dir(inner: &Path, outer: &Path) -> Option<PathBuf> {
+    let inner = fs::canonicalize(inner).ok()?;
+    let outer = fs::canonicalize(outer).ok()?;
+    (inner != outer && inner.starts_with(&outer)).then_some(inner)
+}
+
+/// Dotfiles and dot-directories, like `.env` or `.github`.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+}
+
+/// The source pattern of the first rule matching `path_str`.
+fn matching_rule<'a>(rules: &'a [(String, Regex)], path_str: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|(_, regex)| regex.is_match(path_str))
+        .map(|(pattern, _)| pattern.as_str())
+}
+
+/// The result of reading a file ahead of emitting it.
+enum Loaded {
+    /// Nothing needed to be read (e.g. `--files-only`)
+    Unread,
+    Contents {
+        /// `None` when the file was only read to count tokens or for the manifest
+        text: Option<String>,
+        tokens: Option<usize>,
+        /// Line count and digest of the raw file, for `--manifest` and `--hash`
+        lines: Option<usize>,
+        sha256: Option<String>,
+        /// The lines emitted for a `path:start-end` pattern
+        line_range: Option<(usize, usize)>,
+    },
+    /// Too big to read up front; emitted straight from the file
+    Stream,
+    TooLarge,
+    /// More lines than `--max-lines`, with the count
+    TooManyLines(usize),
+    Binary,
+    /// The content doesn't match `--contains`
+    NoMatch,
+    /// Identical in the `--diff` directory
+    Unchanged,
+    Unreadable(std::io::Error),
+}
+
+impl Loaded {
+    /// Whether the file gets emitted rather than reported as skipped.
+    fn will_emit(&self) -> bool {
+        matches!(self, Loaded::Unread | Loaded::Stream | Loaded::Contents { .. })
+    }
+}
+
+/// How a file in the working directory differs from the `--diff` directory.
+/// The path is the other side's copy.
+enum Change {
+    Added,
+    Removed(PathBuf),
+    Modified(PathBuf),
+}
+
+/// Something `load_file` has to tell the user about a file it read. The
+/// pool threads collect these for the main thread to report.
+enum Note {
+    /// Not UTF-8, so decoded as the encoding it looked like
+    Decoded { path: PathBuf, encoding: &'static str },
+    /// A `path:start-end` range clamped to the file, or `None` if none of it was left
+    RangeAdjusted {
+        path: PathBuf,
+        requested: (usize, usize),
+        actual: Option<(usize, usize)>,
+    },
+}
+
+impl Note {
+    fn message(&self) -> String {
+        match self {
+            Note::Decoded { path, encoding } => {
+                format!("Note: {} is not UTF-8, decoded as {}", path.display(), encoding)
+            }
+            Note::RangeAdjusted {
+                path,
+                requested: (start, end),
+                actual: Some((first, last)),
+            } => format!(
+                "Warning: {}: lines {}-{} adjusted to {}-{}",
+                path.display(),
+                start,
+                end,
+                first,
+                last
+            ),
+            Note::RangeAdjusted {
+                path,
+                requested: (start, end),
+                actual: None,
+            } => format!("Warning: {}: lines {}-{} are past the end of the file", path.display(), start, end),
+        }
+    }
+}
+
+/// Where `load_file` gets a file's bytes.
+enum Source<'a> {
+    Disk,
+    /// An `--archive` member, or `None` if the archive has no such file
+    Member(Option<&'a [u8]>),
+    /// Both sides of a `--diff` file, with its relative path for the diff header
+    Diff(&'a Change, String),
+}
+
+/// Reads and prepares a single file. This runs on the rayon pool, so it only
+/// touches the parsed arguments and, for an archive, its members; anything
+/// to report goes in `notes`.
+#[allow(clippy::too_many_arguments)]
+fn load_file(
+    args: &CliArgs,
+    path: &Path,
+    size: u64,
+    include_content: bool,
+    allow_stream: bool,
+    range: Option<(usize, usize)>,
+    source: &Source,
+    notes: &mut Vec<Note>,
+) -> Loaded {
+    if args.max_file_size.is_some_and(|max_size| size > max_size) {
+        return Loaded::TooLarge;
+    }
+    if let Source::Diff(change, name) = source {
+        return load_diff(args, path, change, name, include_content, notes);
+    }
+    let needs_hash = args.hash || args.manifest.is_some();
+    if !include_content && !args.count_tokens && !needs_hash && args.contains.is_none() && args.max_lines.is_none() {
+        return Loaded::Unread;
+    }
+    let is_binary = || match source {
+        Source::Member(bytes) => bytes.is_some_and(looks_binary),
+        _ => is_binary_file(path).unwrap_or(false),
+    };
+    if (!args.include_binary || args.contains.is_some()) && !forced_utf16(args) && is_binary() {
+        // Binary content is never searched, so it can't match --contains
+        return if args.contains.is_some() { Loaded::NoMatch } else { Loaded::Binary };
+    }
+    // A file in a legacy encoding is read whole instead, so it can be decoded
+    if include_content && allow_stream && should_stream(args, size) && starts_as_utf8(path).unwrap_or(true) {
+        // Counted without holding the file in memory, like the stream itself
+        if let Some(max_lines) = args.max_lines {
+            match count_lines(path) {
+                Ok(lines) if lines > max_lines => return Loaded::TooManyLines(lines),
+                Ok(_) => {}
+                Err(e) => return Loaded::Unreadable(e),
+            }
+        }
+        return Loaded::Stream;
+    }
+
+    let bytes = match source {
+        Source::Member(bytes) => bytes
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "not found in archive")),
+        _ => fs::read(path),
+    };
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(e) => return Loaded::Unreadable(e),
+    };
+    // Hash the raw bytes, before any lossy decoding
+    let sha256 = needs_hash.then(|| sha256_hex(&bytes));
+    let contents = match decode_contents(args, path, bytes, notes) {
+        Ok(contents) => contents,
+        Err(e) => return Loaded::Unreadable(e),
+    };
+    let contents = if args.normalize_newlines {
+        normalize_newlines(&contents)
+    } else {
+        contents
+    };
+    if args.contains.as_ref().is_some_and(|regex| !regex.is_match(&contents)) {
+        return Loaded::NoMatch;
+    }
+    if let Some(max_lines) = args.max_lines {
+        let lines = contents.split_inclusive('\n').count();
+        if lines > max_lines {
+            return Loaded::TooManyLines(lines);
+        }
+    }
+    let lines = args.manifest.is_some().then(|| contents.lines().count());
+
+    let (contents, line_range) = match range {
+        Some((start, end)) => {
+            let selection = select_lines(&contents, start, end);
+            if selection.adjusted {
+                notes.push(Note::RangeAdjusted {
+                    path: path.to_path_buf(),
+                    requested: (start, end),
+                    actual: selection.range,
+                });
+            }
+            (selection.text, selection.range)
+        }
+        None => (contents, None),
+    };
+    let contents = match strip_syntax_for_path(path).filter(|_| args.strip_comments) {
+        Some(syntax) => {
+            let block = syntax.block.filter(|_| args.strip_block_comments);
+            strip_comments(&contents, syntax.line, block)
+        }
+        None => contents,
+    };
+    let contents = if args.strip_blank_lines {
+        strip_blank_lines(&contents)
+    } else {
+        contents
+    };
+    let (contents, gutter) = if args.line_numbers {
+        let first = line_range.map_or(1, |(first, _)| first);
+        (number_lines(&contents, first), gutter_width(&contents, first))
+    } else {
+        (contents, 0)
+    };
+    // Numbered first, so the kept lines keep their real numbers
+    let contents = if args.head.is_some() || args.tail.is_some() {
+        truncate_lines(&contents, args.head, args.tail)
+    } else {
+        contents
+    };
+    // Wrapped last, so wrapped rows don't count as lines for numbering or truncation
+    let contents = match args.wrap {
+        Some(cols) => wrap_lines(&contents, cols, gutter),
+        None => contents,
+    };
+
+    let tokens = args.count_tokens.then(|| estimate_tokens(&contents));
+    Loaded::Contents {
+        text: include_content.then_some(contents),
+        tokens,
+        lines,
+        sha256,
+        line_range,
+    }
+}
+
+/// Reads both sides of a `--diff` file. Its content is the unified diff
+/// between them, without the transformations applied to ordinary files.
+fn load_diff(
+    args: &CliArgs,
+    path: &Path,
+    change: &Change,
+    name: &str,
+    include_content: bool,
+    notes: &mut Vec<Note>,
+) -> Loaded {
+    let (new_path, old_path) = match change {
+        Change::Added => (Some(path), None),
+        Change::Removed(old) => (None, Some(old.as_path())),
+        Change::Modified(old) => (Some(path), Some(old.as_path())),
+    };
+    let read = |path: Option<&Path>| path.map(fs::read).transpose();
+    let (new, old) = match (read(new_path), read(old_path)) {
+        (Ok(new), Ok(old)) => (new, old),
+        (Err(e), _) | (_, Err(e)) => return Loaded::Unreadable(e),
+    };
+    if new == old {
+        return Loaded::Unchanged;
+    }
+    if !args.include_binary && !forced_utf16(args) && new.iter().chain(&old).any(|bytes| looks_binary(bytes)) {
+        return Loaded::Binary;
+    }
+
+    let mut decode = |path: Option<&Path>, bytes: Option<Vec<u8>>| match (path, bytes) {
+        (Some(path), Some(bytes)) => decode_contents(args, path, bytes, notes).map(Some),
+        _ => Ok(None),
+    };
+    let (new, old) = match (decode(new_path, new), decode(old_path, old)) {
+        (Ok(new), Ok(old)) => (new, old),
+        (Err(e), _) | (_, Err(e)) => return Loaded::Unreadable(e),
+    };
+    let diff = unified_diff(old.as_deref(), new.as_deref(), name);
+    let tokens = args.count_tokens.then(|| estimate_tokens(&diff));
+    Loaded::Contents {
+        text: include_content.then_some(diff),
+        tokens,
+        lines: None,
+        sha256: None,
+        line_range: None,
+    }
+}
+
+/// Whether a file is big enough to stream rather than read into memory, and
+/// nothing asked for needs its whole content at once. Only plain output can
+/// be written line by line; JSON and markdown fences need the full text.
+fn should_stream(args: &CliArgs, size: u64) -> bool {
+    size > STREAM_THRESHOLD
+        && args.format == OutputFormat::Plain
+        && !args.count_tokens
+        && !args.hash
+        && args.manifest.is_none()
+        && !args.strip_comments
+        && !args.strip_blank_lines
+        && !args.normalize_newlines
+        && args.wrap.is_none()
+        && args.template_dir.is_none()
+        && !args.include_binary
+        && args.archive.is_none()
+        && args.encoding.is_none()
+        && args.contains.is_none()
+}
+
+/// Counts lines the way `str::split_inclusive('\n')` would, without holding
+/// the file in memory.
+fn count_lines(path: &Path) -> std::io::Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut count = 0;
+    let mut last = b'\n';
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            break;
+        }
+        count += buffer.iter().filter(|&&byte| byte == b'\n').count();
+        last = buffer[buffer.len() - 1];
+        let consumed = buffer.len();
+        reader.consume(consumed);
+    }
+    // A final line without a newline still counts
+    Ok(if last == b'\n' { count } else { count + 1 })
+}
+
+/// Whether `--encoding` is UTF-16, whose text is full of null bytes and
+/// would otherwise look binary.
+fn forced_utf16(args: &CliArgs) -> bool {
+    args.encoding.is_some_and(|encoding| encoding == UTF_16LE || encoding == UTF_16BE)
+}
+
+/// Decodes a file for emission: with the `--encoding` given, as UTF-8 when
+/// it's valid, and otherwise in the legacy encoding the content looks like
+/// (noting in `notes` which was picked). `--include-binary` decodes anything other
+/// than UTF-8 lossily instead. Bytes the encoding can't map are an error,
+/// except in JSON output, where they're replaced.
+fn decode_contents(
+    args: &CliArgs,
+    path: &Path,
+    bytes: Vec<u8>,
+    notes: &mut Vec<Note>,
+) -> std::io::Result<String> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+    if let Some(encoding) = args.encoding {
+        let (text, had_errors) = encoding.decode_with_bom_removal(&bytes);
+        if had_errors && !args.format.is_json() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("not valid {}", encoding.name()),
+            ));
+        }
+        return Ok(text.into_owned());
+    }
+
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) => return Ok(text),
+        Err(e) => e.into_bytes(),
+    };
+    if args.include_binary {
+        return Ok(String::from_utf8_lossy(&bytes).into_owned());
+    }
+
+    let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
+    detector.feed(&bytes, true);
+    let encoding = detector.guess(None, Utf8Detection::Deny);
+    let (text, had_errors) = encoding.decode_without_bom_handling(&bytes);
+    if had_errors && !args.format.is_json() {
+        return Err(invalid());
+    }
+    notes.push(Note::Decoded {
+        path: path.to_path_buf(),
+        encoding: encoding.name(),
+    });
+    Ok(text.into_owned())
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir holding `files`, each
+    /// containing its own path.
+    fn fixture(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agg-files-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, file).unwrap();
+        }
+        dir
+    }
+
+    fn processor(dir: &Path, args: &[&str]) -> FileProcessor {
+        let argv = std::iter::once("agg-files").chain(args.iter().copied()).map(String::from);
+        let args = CliArgs::parse_from(argv).unwrap();
+        FileProcessor::without_output(args, dir.to_path_buf(), Config::default()).unwrap()
+    }
+
+    /// What a run with `args` in `dir` writes, by way of an `--output` file
+    /// beside the directory.
+    fn run(dir: &Path, args: &[&str]) -> String {
+        String::from_utf8(run_bytes(dir, args)).unwrap()
+    }
+
+    fn run_bytes(dir: &Path, args: &[&str]) -> Vec<u8> {
+        let out = dir.with_extension("out");
+        let argv: Vec<&str> = ["agg-files", "-o", out.to_str().unwrap()].into_iter().chain(args.iter().copied()).collect();
+        let args = CliArgs::parse_from(argv.into_iter().map(String::from)).unwrap();
+        let output = Output::create(&args).unwrap();
+        FileProcessor::new(args, dir.to_path_buf(), output, Config::default()).unwrap().process().unwrap();
+        let written = fs::read(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        written
+    }
+
+    /// The paths `args` select in `dir`, relative to it, in output order.
+    fn selected(dir: &Path, args: &[&str]) -> Vec<String> {
+        let processor = processor(dir, args);
+        processor
+            .ordered_files()
+            .into_iter()
+            .map(|(path, _)| match_subject(&processor.relative_path(&path)))
+            .collect()
+    }
+
+    #[test]
+    fn aggregate_returns_contents_without_writing_output() {
+        let dir = fixture("aggregate", &["src/main.rs", "src/lib.rs", "notes.txt"]);
+        let argv = ["agg-files", "-r", "*.rs"].map(String::from);
+        let args = CliArgs::parse_from(argv).unwrap();
+        let files = FileProcessor::without_output(args, dir.clone(), Config::default()).unwrap().aggregate();
+
+        let found: Vec<(PathBuf, Option<String>)> = files
+            .into_iter()
+            .map(|file| (file.path.strip_prefix(&dir).unwrap().to_path_buf(), file.content))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (PathBuf::from("src/lib.rs"), Some("src/lib.rs".to_string())),
+                (PathBuf::from("src/main.rs"), Some("src/main.rs".to_string())),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_dir_is_only_strictly_inside() {
+        let dir = fixture("nested", &["old/a.rs"]);
+        let canonical = fs::canonicalize(&dir).unwrap();
+        assert_eq!(nested_dir(&dir.join("old"), &dir), Some(canonical.join("old")));
+        assert_eq!(nested_dir(&dir, &dir), None);
+        assert_eq!(nested_dir(&dir, &dir.join("old")), None);
+        assert_eq!(nested_dir(&dir.join("missing"), &dir), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dot_relative_prefixes_only_paths_below() {
+        assert_eq!(dot_relative(Path::new("src/main.rs")), "./src/main.rs");
+        assert_eq!(dot_relative(Path::new("./src/main.rs")), "./src/main.rs");
+        assert_eq!(dot_relative(Path::new("../README.md")), "../README.md");
+        assert_eq!(dot_relative(Path::new("/etc/hosts")), "/etc/hosts");
+    }
+
+    #[test]
+    fn paths_outside_the_working_directory_are_shown_as_given() {
+        let dir = fixture("display", &["inner/a.rs", "b.rs"]);
+        let processor = processor(&dir.join("inner"), &[]);
+        assert_eq!(processor.display_path(&dir.join("inner").join("a.rs")), "./a.rs");
+        assert_eq!(processor.display_path(&dir.join("inner").join("../b.rs")), "../b.rs");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appending_needs_the_manifest_of_an_existing_output() {
+        let dir = fixture("append", &[]);
+        fs::create_dir_all(&dir).unwrap();
+        let (output, manifest) = (dir.join("out.txt"), dir.join("out.json"));
+
+        assert!(read_manifest(&manifest, &output).unwrap().is_empty());
+        fs::write(&manifest, r#"[{"path":"./a.rs","size":1,"lines":1,"sha256":"00"}]"#).unwrap();
+        assert!(read_manifest(&manifest, &output).unwrap().is_empty());
+
+        fs::write(&output, "# File: ./a.rs\na\n").unwrap();
+        let entries = read_manifest(&manifest, &output).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "./a.rs");
+
+        fs::remove_file(&manifest).unwrap();
+        let error = read_manifest(&manifest, &output).err().unwrap();
+        assert!(error.contains("manifest") && error.contains("is missing"), "{}", error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appending_adds_only_new_files() {
+        let dir = fixture("append-output", &["a.rs", "b.rs"]);
+        let (out, manifest) = (dir.with_extension("out"), dir.with_extension("json"));
+        let _ = fs::remove_file(&out);
+        let _ = fs::remove_file(&manifest);
+        let append = |patterns: &[&str]| {
+            let argv = ["agg-files", "--append-output", "-o", out.to_str().unwrap(), "--manifest", manifest.to_str().unwrap()];
+            let args = CliArgs::parse_from(argv.iter().chain(patterns).map(|arg| arg.to_string())).unwrap();
+            let output = Output::create(&args).unwrap();
+            FileProcessor::new(args, dir.clone(), output, Config::default()).unwrap().process().unwrap();
+            let written = fs::read_to_string(&out).unwrap();
+            written.lines().filter_map(|line| line.strip_prefix("# File: ")).map(str::to_string).collect::<Vec<_>>()
+        };
+
+        assert_eq!(append(&["a.rs"]), ["./a.rs"]);
+        assert_eq!(append(&["*.rs"]), ["./a.rs", "./b.rs"]);
+        assert_eq!(append(&["*.rs"]), ["./a.rs", "./b.rs"]);
+        // A file whose content changed is added again
+        fs::write(dir.join("a.rs"), "fn a() {}\n").unwrap();
+        assert_eq!(append(&["*.rs"]), ["./a.rs", "./b.rs", "./a.rs"]);
+
+        let entries: Vec<ManifestEntry> = serde_json::from_str(&fs::read_to_string(&manifest).unwrap()).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(), ["./a.rs", "./b.rs", "./a.rs"]);
+        fs::remove_file(&out).unwrap();
+        fs::remove_file(&manifest).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn groups_sort_by_name_with_extensionless_files_last() {
+        let dir = fixture("groups", &["z.txt", "b.rs", "Makefile", "a.py", "c.toml", "notes.md", "LICENSE", "a.rs"]);
+        let order = selected(&dir, &["--group-by-ext", "*"]);
+        assert_eq!(order, ["Makefile", "notes.md", "a.py", "a.rs", "b.rs", "c.toml", "z.txt", "LICENSE"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn each_group_gets_one_section_header() {
+        let dir = fixture("group-headers", &["b.rs", "a.rs", "LICENSE", "notes.md"]);
+        let output = run(&dir, &["--group-by-ext", "*"]);
+        let headers: Vec<&str> = output.lines().filter(|line| line.starts_with("### ")).collect();
+        assert_eq!(headers, ["### Markdown files", "### Rust files", "### Other files"]);
+        let rust = output.find("### Rust files").unwrap();
+        assert!(rust < output.find("a.rs").unwrap() && output.find("b.rs").unwrap() < output.find("### Other").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn excludes_win_over_patterns() {
+        let dir = fixture("exclude", &["src/main.rs", "src/gen.rs", "target/out.rs", "build.rs"]);
+        assert_eq!(
+            selected(&dir, &["-r", "*.rs", "--exclude", "gen.rs", "--exclude", "target"]),
+            ["build.rs", "src/main.rs"]
+        );
+        // Named outright, still excluded, and the same when sorting by size
+        assert_eq!(selected(&dir, &["src/gen.rs", "--exclude", "src/*.rs"]), Vec::<String>::new());
+        assert_eq!(selected(&dir, &["--sort", "size", "-r", "*.rs", "--exclude", "src"]), ["target/out.rs", "build.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_gitignore_only_affects_its_subtree() {
+        let dir = fixture("nested-gitignore", &["a/secret.txt", "a/keep.txt", "b/secret.txt"]);
+        fs::write(dir.join("a/.gitignore"), "secret.txt\n").unwrap();
+        assert_eq!(selected(&dir, &["-r", "*.txt"]), ["a/keep.txt", "b/secret.txt"]);
+        assert_eq!(selected(&dir, &["-r", "-i", "*.txt"]), ["a/keep.txt", "a/secret.txt", "b/secret.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn headers_are_the_same_in_every_mode() {
+        let dir = fixture("headers", &["src/main.rs"]);
+        let expected = "# File: ./src/main.rs\nsrc/main.rs\n";
+        assert!(run(&dir, &["src/main.rs"]).starts_with(expected));
+        assert!(run(&dir, &["-r", "*.rs"]).starts_with(expected));
+        assert!(run(&dir, &["src"]).starts_with(expected));
+        assert!(run(&dir, &["./src/main.rs"]).starts_with(expected));
+        // Size order lists the files with their sizes
+        assert_eq!(run(&dir, &["--sort", "size", "src/main.rs"]), "# File: ./src/main.rs (11 bytes)\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relative_to_an_ancestor_or_elsewhere() {
+        let dir = fixture("relative-to", &["proj/src/a.rs", "other/b.rs"]);
+        let working_dir = dir.join("proj");
+        let file = working_dir.join("src/a.rs");
+
+        let ancestor = processor(&working_dir, &["--relative-to", dir.to_str().unwrap()]);
+        assert_eq!(ancestor.display_path(&file), "./proj/src/a.rs");
+        let unrelated = processor(&working_dir, &["--relative-to", dir.join("other").to_str().unwrap()]);
+        let absolute = fs::canonicalize(&file).unwrap();
+        assert_eq!(unrelated.display_path(&file), absolute.display().to_string());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ext_narrows_patterns_case_insensitively() {
+        let dir = fixture("ext", &["a.RS", "b.rs", "c.toml", "d.md", "src/e.Rs"]);
+        assert_eq!(selected(&dir, &["--ext", "rs", "*"]), ["a.RS", "b.rs"]);
+        assert_eq!(selected(&dir, &["--ext", "RS,toml", "-r", "*"]), ["a.RS", "b.rs", "c.toml", "src/e.Rs"]);
+        assert_eq!(selected(&dir, &["--ext", ".rs", "--ext", "md", "-r", "*", "--exclude", "b.rs"]), ["a.RS", "d.md", "src/e.Rs"]);
+        assert_eq!(selected(&dir, &["--ext", "rs", "*.toml"]), Vec::<String>::new());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_are_followed_only_with_the_flag() {
+        let dir = fixture("symlink", &["proj/main.rs", "shared/a.rs"]);
+        let proj = dir.join("proj");
+        std::os::unix::fs::symlink(dir.join("shared"), proj.join("link")).unwrap();
+        // Loops back to an ancestor are skipped rather than followed forever
+        std::os::unix::fs::symlink(&proj, dir.join("shared/loop")).unwrap();
+        assert_eq!(selected(&proj, &["-r", "*.rs"]), ["main.rs"]);
+        assert_eq!(selected(&proj, &["-r", "-L", "*.rs"]), ["link/a.rs", "main.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_depth_limits_how_far_the_walk_goes() {
+        let dir = fixture("depth", &["a.rs", "one/b.rs", "one/two/c.rs", "one/two/three/d.rs"]);
+        assert_eq!(selected(&dir, &["--max-depth", "1", "*.rs"]), ["a.rs"]);
+        assert_eq!(selected(&dir, &["--max-depth", "2", "*.rs"]), ["a.rs", "one/b.rs"]);
+        // Overrides -r, and limits directories named outright, counting from them
+        assert_eq!(selected(&dir, &["-r", "--max-depth", "3", "*.rs"]), ["a.rs", "one/b.rs", "one/two/c.rs"]);
+        assert_eq!(selected(&dir, &["--max-depth", "1", "one"]), ["one/b.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overlapping_patterns_emit_each_file_once() {
+        let dir = fixture("dedup", &["src/main.rs", "src/lib.rs", "Cargo.toml"]);
+        assert_eq!(
+            selected(&dir, &["-r", "src/main.rs", "*.rs", "src", "./src/lib.rs", "*"]),
+            ["src/main.rs", "src/lib.rs", "Cargo.toml"]
+        );
+        let output = run(&dir, &["src/main.rs", "src/main.rs", "src/../src/main.rs"]);
+        assert_eq!(output.matches("# File:").count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn header_template_is_rendered_for_each_file() {
+        let dir = fixture("header-template", &["src/main.rs"]);
+        fs::write(dir.join("src/main.rs"), "fn main() {\n}\n").unwrap();
+        let output = run(&dir, &["--header-template", "<<{path}|{size}|{lines}|{ext}>>", "src/main.rs"]);
+        assert!(output.starts_with("<<./src/main.rs|14|2|rs>>\nfn main() {\n}\n"), "{}", output);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tool_ignore_files_apply_even_without_gitignore() {
+        let dir = fixture("ignore-files", &["a.rs", "b.rs", "c.rs", "sub/d.rs"]);
+        fs::write(dir.join(".ignore"), "a.rs\n").unwrap();
+        fs::write(dir.join(".agg-filesignore"), "b.rs\n").unwrap();
+        fs::write(dir.join("sub/.aggignore"), "d.rs\n").unwrap();
+        assert_eq!(selected(&dir, &["-r", "*.rs"]), ["c.rs"]);
+        assert_eq!(selected(&dir, &["-r", "-i", "*.rs"]), ["c.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_total_bytes_stops_at_the_boundary() {
+        let dir = fixture("total-bytes", &["a.txt", "b.txt", "c.txt"]);
+        let headers = |output: &str| output.lines().filter(|line| line.starts_with("# File:")).count();
+        // Each file holds its own 5-byte name
+        let output = run(&dir, &["--max-total-bytes", "10", "*.txt"]);
+        assert_eq!(headers(&output), 2);
+        assert!(output.contains("./b.txt") && !output.contains("./c.txt"), "{}", output);
+        assert!(output.ends_with("# Output truncated: --max-total-bytes reached, 1 more files not emitted\n"));
+        assert_eq!(headers(&run(&dir, &["--max-total-bytes", "9", "*.txt"])), 1);
+        assert_eq!(headers(&run(&dir, &["--max-total-bytes", "15", "*.txt"])), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncation_notices_only_count_files_that_would_be_emitted() {
+        let dir = fixture("truncation-count", &["a.txt", "b.txt", "c.txt"]);
+        fs::write(dir.join("c.txt"), b"\0binary").unwrap();
+        let output = run(&dir, &["--max-total-bytes", "5", "*.txt"]);
+        assert!(output.ends_with("# Output truncated: --max-total-bytes reached, 1 more files not emitted\n"), "{}", output);
+        let output = run(&dir, &["--max-files", "1", "*.txt"]);
+        assert!(output.ends_with("# Output truncated: --max-files reached, 1 more files not emitted\n"), "{}", output);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_files_emits_exactly_that_many() {
+        let dir = fixture("max-files", &["0.txt", "a.txt", "bbbb.txt", "cc.txt"]);
+        // Skipped files don't count towards the cap
+        fs::write(dir.join("0.txt"), b"\0binary").unwrap();
+        let headers = |output: &str| -> Vec<String> {
+            output.lines().filter(|line| line.starts_with("# File:")).map(str::to_string).collect()
+        };
+        let output = run(&dir, &["--max-files", "2", "*.txt"]);
+        assert_eq!(headers(&output), ["# File: ./a.txt", "# File: ./bbbb.txt"]);
+        assert!(output.ends_with("# Output truncated: --max-files reached, 1 more files not emitted\n"), "{}", output);
+        assert_eq!(headers(&run(&dir, &["--max-files", "3", "*.txt"])).len(), 3);
+
+        // The largest ones when sorting by size
+        let output = run(&dir, &["--max-files", "2", "--sort", "size", "*.txt"]);
+        assert_eq!(headers(&output), ["# File: ./bbbb.txt (8 bytes)", "# File: ./0.txt (7 bytes)"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skip_generated_leaves_out_lock_files_and_bundles() {
+        let dir = fixture("generated", &["Cargo.lock", "Cargo.toml", "package-lock.json", "web/app.js", "web/app.min.js", "out.snap"]);
+        assert_eq!(selected(&dir, &["--skip-generated", "-r", "*"]), ["Cargo.toml", "out.snap", "web/app.js"]);
+        assert_eq!(selected(&dir, &["-r", "*"]).len(), 6);
+
+        // The config adds patterns and can drop built-in ones
+        let config = Config {
+            skip_generated: Some(vec!["!Cargo.lock".to_string(), "*.snap".to_string()]),
+            ..Config::default()
+        };
+        let args = CliArgs::parse_from(["agg-files", "--skip-generated", "-r", "*"].map(String::from)).unwrap();
+        let processor = FileProcessor::without_output(args, dir.clone(), config).unwrap();
+        let found: Vec<String> = processor
+            .ordered_files()
+            .into_iter()
+            .map(|(path, _)| match_subject(&processor.relative_path(&path)))
+            .collect();
+        assert_eq!(found, ["Cargo.lock", "Cargo.toml", "web/app.js"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn generated_patterns_extend_the_built_in_list() {
+        let patterns = generated_patterns(&["!*.map".to_string(), "*.snap".to_string()]);
+        assert!(patterns.contains(&"Cargo.lock".to_string()) && patterns.contains(&"*.min.js".to_string()));
+        assert!(!patterns.contains(&"*.map".to_string()) && !patterns.iter().any(|pattern| pattern.starts_with('!')));
+        assert_eq!(patterns.last().unwrap(), "*.snap");
+        assert_eq!(generated_patterns(&[]).len(), GENERATED_FILES.len());
+    }
+
+    #[test]
+    fn xml_output_parses_back_to_the_files() {
+        use quick_xml::events::Event;
+        use quick_xml::XmlVersion;
+
+        let dir = fixture("xml", &["a&\"b\".txt", "cdata.txt", "empty.txt"]);
+        fs::write(dir.join("cdata.txt"), "<tag> ]]> & ]]]]>\n").unwrap();
+        fs::write(dir.join("empty.txt"), "").unwrap();
+        let output = run(&dir, &["--format", "xml", "*.txt"]);
+
+        let mut reader = quick_xml::Reader::from_str(&output);
+        let mut files = Vec::new();
+        let mut depth = 0;
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(element) | Event::Empty(element) if element.name().as_ref() == b"file" => {
+                    assert_eq!(depth, 1, "file outside <files>");
+                    let path = element.try_get_attribute("path").unwrap().unwrap();
+                    files.push((path.normalized_value(XmlVersion::Implicit1_0).unwrap().into_owned(), String::new()));
+                }
+                Event::Start(element) => {
+                    assert_eq!(element.name().as_ref(), b"files");
+                    depth += 1;
+                }
+                Event::End(element) if element.name().as_ref() == b"files" => depth -= 1,
+                Event::CData(text) => files.last_mut().unwrap().1.push_str(&text.decode().unwrap()),
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        assert_eq!(depth, 0);
+        assert_eq!(
+            files,
+            [
+                ("./a&\"b\".txt".to_string(), "a&\"b\".txt".to_string()),
+                ("./cdata.txt".to_string(), "<tag> ]]> & ]]]]>\n".to_string()),
+                ("./empty.txt".to_string(), String::new()),
+            ]
+        );
+        // Control characters XML can't hold are replaced
+        assert_eq!(xml_cdata("a\u{1}b"), "<![CDATA[a\u{fffd}b]]>");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prepend_and_append_wrap_the_output_verbatim() {
+        let dir = fixture("wrap", &["a.rs", "b.rs"]);
+        // Filters don't apply to the wrapper files
+        fs::write(dir.join(".before.md"), "Review this:\n  # not a header\n\n").unwrap();
+        fs::write(dir.join("after.rs"), "Thanks!").unwrap();
+        let before = dir.join(".before.md");
+        let after = dir.join("after.rs");
+        let output = run(
+            &dir,
+            &["--prepend", before.to_str().unwrap(), "--append", after.to_str().unwrap(), "--exclude", "after.rs", "*.rs"],
+        );
+        assert!(output.starts_with("Review this:\n  # not a header\n\n# File: ./a.rs\n"), "{}", output);
+        assert!(output.ends_with("b.rs\n\n=====================\n\nThanks!\n"), "{}", output);
+        assert_eq!(output.matches("# File:").count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn git_directory_is_only_read_with_include_git() {
+        let dir = fixture("include-git", &[".git/hooks/pre-commit", ".git/config", "src/a.rs"]);
+        fs::write(dir.join(".gitignore"), "config\n").unwrap();
+        assert_eq!(selected(&dir, &["-r", "*"]), ["src/a.rs"]);
+        assert_eq!(selected(&dir, &["-r", "pre-commit"]), Vec::<String>::new());
+        assert_eq!(selected(&dir, &["--include-git", "-r", "pre-commit"]), [".git/hooks/pre-commit"]);
+        // Gitignore still applies
+        assert_eq!(selected(&dir, &["--include-git", "-r", "*"]), [".git/hooks/pre-commit", "src/a.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stats_breakdown_adds_up_to_the_totals() {
+        let dir = fixture("stats", &["a.rs", "src/b.rs", "C.RS", "data.json", "Makefile", "skip.bin"]);
+        fs::write(dir.join("skip.bin"), b"\0\0").unwrap();
+        let out = dir.with_extension("out");
+        let args = CliArgs::parse_from(["agg-files", "--stats", "-o", out.to_str().unwrap(), "-r", "*"].map(String::from)).unwrap();
+        let output = Output::create(&args).unwrap();
+        let processor = FileProcessor::new(args, dir.clone(), output, Config::default()).unwrap();
+        processor.process().unwrap();
+
+        let stats = processor.stats.borrow();
+        assert_eq!((stats.files, stats.skipped_binary), (5, 1));
+        let by_extension = &stats.by_extension;
+        assert_eq!(by_extension["rs"], (3, "a.rs".len() as u64 + "src/b.rs".len() as u64 + "C.RS".len() as u64));
+        assert_eq!(by_extension["json"], (1, "data.json".len() as u64));
+        assert_eq!(by_extension[""], (1, "Makefile".len() as u64));
+        assert_eq!(by_extension.values().map(|(files, _)| files).sum::<usize>(), stats.files);
+        assert_eq!(by_extension.values().map(|(_, bytes)| bytes).sum::<u64>(), stats.bytes);
+        fs::remove_file(&out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_dir_mirrors_the_source_tree() {
+        let dir = fixture("mirror", &["src/a.rs", "src/nested/b.rs", "top.md", "skip.txt"]);
+        let mirror = dir.with_extension("mirror");
+        let _ = fs::remove_dir_all(&mirror);
+        processor(&dir, &["--output-dir", mirror.to_str().unwrap(), "-n", "-r", "*.rs", "*.md"]).process().unwrap();
+
+        let mut written: Vec<String> = WalkDir::new(&mirror)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| match_subject(entry.path().strip_prefix(&mirror).unwrap()))
+            .collect();
+        written.sort();
+        assert_eq!(written, ["src/a.rs", "src/nested/b.rs", "top.md"]);
+        // Each copy goes through the same processing as aggregated output
+        assert_eq!(fs::read_to_string(mirror.join("src/nested/b.rs")).unwrap(), "1 | src/nested/b.rs");
+        fs::remove_dir_all(&mirror).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn contains_keeps_only_files_whose_content_matches() {
+        let dir = fixture("contains", &["a.rs", "b.rs", "c.rs", "d.bin"]);
+        fs::write(dir.join("a.rs"), "// TODO: split\nfn a() {}\n").unwrap();
+        fs::write(dir.join("c.rs"), "fn c() {} // todo\n").unwrap();
+        fs::write(dir.join("d.bin"), b"\0TODO").unwrap();
+        let headers = |args: &[&str]| -> Vec<String> {
+            run(&dir, args).lines().filter(|line| line.starts_with("# File:")).map(str::to_string).collect()
+        };
+        assert_eq!(headers(&["--contains", "TODO", "*"]), ["# File: ./a.rs"]);
+        assert_eq!(headers(&["--contains", "(?i)todo", "*"]), ["# File: ./a.rs", "# File: ./c.rs"]);
+        // Binary files aren't scanned even when they'd otherwise be included
+        assert_eq!(headers(&["--contains", "TODO", "--include-binary", "*"]), ["# File: ./a.rs"]);
+        assert_eq!(headers(&["--contains", "^b\\.rs$", "*"]), ["# File: ./b.rs"]);
+        assert!(headers(&["--contains", "nowhere", "*"]).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn toc_offsets_point_at_the_headers() {
+        let names: Vec<String> = (0..12).map(|i| format!("src/file{:02}.rs", i)).collect();
+        let dir = fixture("toc", &names.iter().map(String::as_str).collect::<Vec<_>>());
+        for args in [&["--toc", "-r", "*.rs"][..], &["--toc", "--format", "markdown", "-r", "*.rs"]] {
+            let output = run_bytes(&dir, args);
+            let text = String::from_utf8_lossy(&output).into_owned();
+            let toc: Vec<(usize, &str)> = text
+                .lines()
+                .skip(1)
+                .take_while(|line| !line.is_empty())
+                .map(|line| {
+                    let (offset, path) = line.strip_prefix("# ").unwrap().split_once(' ').unwrap();
+                    (offset.parse().unwrap(), path)
+                })
+                .collect();
+            assert_eq!(toc.len(), names.len(), "{}", text);
+            for (offset, path) in toc {
+                let header = String::from_utf8_lossy(&output[offset..]);
+                let line = header.lines().next().unwrap();
+                assert!(line.starts_with('#') && line.contains(path), "{} at {}: {}", path, offset, header);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backslash_patterns_match_forward_slash_paths() {
+        let dir = fixture("backslash", &["src/main.rs", "src/gen/out.rs", "lib/src/x.rs"]);
+        assert_eq!(selected(&dir, &["-r", r"src\*.rs"]), ["src/main.rs"]);
+        assert_eq!(selected(&dir, &["-r", "*.rs", "--exclude", r"src\gen"]), ["lib/src/x.rs", "src/main.rs"]);
+
+        let config = Config {
+            ignore: Some(vec![r"lib\src".to_string()]),
+            ..Config::default()
+        };
+        let args = CliArgs::parse_from(["agg-files", "-r", "*.rs"].map(String::from)).unwrap();
+        let processor = FileProcessor::without_output(args, dir.clone(), config).unwrap();
+        let found: Vec<String> = processor
+            .ordered_files()
+            .into_iter()
+            .map(|(path, _)| match_subject(&processor.relative_path(&path)))
+            .collect();
+        assert_eq!(found, ["src/gen/out.rs", "src/main.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_lines_output_parses_one_file_per_line() {
+        let dir = fixture("jsonl", &["a.rs", "b.txt", "c.md"]);
+        fs::write(dir.join("b.txt"), "two\nlines \"quoted\"\r\n").unwrap();
+        let output = run(&dir, &["--format", "jsonl", "--hash", "*"]);
+        let files: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(files.len(), 3, "{}", output);
+        assert_eq!(files[0]["path"], "./a.rs");
+        assert_eq!(files[1]["content"], "two\nlines \"quoted\"\r\n");
+        assert_eq!(files[1]["size"], 20);
+        assert!(files.iter().all(|file| file["sha256"].as_str().is_some_and(|hash| hash.len() == 64)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crlf_files_are_kept_unless_normalized() {
+        let dir = fixture("crlf", &["win.txt"]);
+        fs::write(dir.join("win.txt"), "one\r\ntwo\r\nthree\r\n").unwrap();
+        let output = run(&dir, &["win.txt"]);
+        assert!(output.contains("one\r\ntwo\r\nthree\r\n"), "{:?}", output);
+        let output = run(&dir, &["--normalize-newlines", "win.txt"]);
+        assert!(output.contains("one\ntwo\nthree\n") && !output.contains('\r'), "{:?}", output);
+
+        // Line numbers and counts see three lines either way
+        let output = run(&dir, &["-n", "--header-template", "{lines}", "win.txt"]);
+        assert!(output.starts_with("3\n1 | one\r\n2 | two\r\n3 | three\r\n"), "{:?}", output);
+        let output = run(&dir, &["-n", "--normalize-newlines", "--header-template", "{lines}", "win.txt"]);
+        assert!(output.starts_with("3\n1 | one\n2 | two\n3 | three\n"), "{:?}", output);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn archive_members_go_through_the_same_filters() {
+        use std::io::Write;
+
+        let dir = fixture("archive", &[]);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("release.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        for (name, bytes) in [("src/main.rs", "fn main() {}\n"), ("src/gen.rs", "// gen\n"), ("README.md", "# hi\n")] {
+            writer.start_file(name, zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(bytes.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let archive = path.to_str().unwrap();
+        assert_eq!(selected(&dir, &["--archive", archive, "-r", "*.rs", "--exclude", "gen.rs"]), ["src/main.rs"]);
+        let output = run(&dir, &["--archive", archive, "-r", "*"]);
+        assert!(output.contains("./src/main.rs") && output.contains("fn main() {}\n") && output.contains("# hi\n"), "{}", output);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diff_marks_modified_added_and_removed_files() {
+        let old = fixture("diff-old", &["same.txt", "changed.txt", "gone.txt"]);
+        let new = fixture("diff-new", &["same.txt", "changed.txt", "added.txt"]);
+        fs::write(new.join("changed.txt"), "changed.txt\nmore\n").unwrap();
+        let output = run(&new, &["--diff", old.to_str().unwrap(), "*.txt"]);
+        let headers: Vec<&str> = output.lines().filter(|line| line.starts_with("# File:")).collect();
+        // Identical files are left out
+        assert_eq!(headers, ["# File: ./added.txt (added)", "# File: ./changed.txt (modified)", "# File: ./gone.txt (removed)"]);
+        assert!(output.contains("--- /dev/null\n+++ b/added.txt\n@@ -0,0 +1 @@\n+added.txt\n"), "{}", output);
+        assert!(output.contains("--- a/changed.txt\n+++ b/changed.txt\n@@ -1 +1,2 @@\n"), "{}", output);
+        assert!(output.contains("+changed.txt\n+more\n"), "{}", output);
+        assert!(output.contains("--- a/gone.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-gone.txt\n"), "{}", output);
+        fs::remove_dir_all(&old).unwrap();
+        fs::remove_dir_all(&new).unwrap();
+    }
+
+    #[test]
+    fn empty_dirs_are_noted_in_the_tree_and_dry_run() {
+        let dir = fixture("empty-dirs", &["src/main.rs"]);
+        fs::create_dir_all(dir.join("src/empty/deeper")).unwrap();
+        let output = run(&dir, &["--tree", "--emit-empty-dirs", "-r", "*.rs"]);
+        assert!(output.starts_with(".\n└── src\n    ├── empty\n    │   └── deeper/ (empty)\n    └── main.rs\n"), "{}", output);
+
+        let output = run(&dir, &["--dry-run", "--emit-empty-dirs", "-r", "*.rs"]);
+        assert_eq!(output, "./src/main.rs\n./src/empty/deeper/ (empty)\n1 files, 11 bytes, 1 empty directories\n");
+        assert!(!run(&dir, &["--tree", "-r", "*.rs"]).contains("(empty)"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn latin1_files_are_decoded() {
+        let dir = fixture("latin1", &["menu.txt", "forced.txt"]);
+        fs::write(dir.join("menu.txt"), b"// Le caf\xe9 cr\xe8me br\xfbl\xe9e, d\xe9j\xe0 pr\xeat\n").unwrap();
+        let output = run(&dir, &["--quiet", "menu.txt"]);
+        assert!(output.contains("// Le café crème brûlée, déjà prêt\n"), "{}", output);
+
+        // `--encoding` decides instead of detection
+        fs::write(dir.join("forced.txt"), b"\xa4 \xbd").unwrap();
+        assert!(run(&dir, &["--encoding", "latin1", "forced.txt"]).contains("¤ ½"));
+        assert!(run(&dir, &["--encoding", "iso-8859-15", "forced.txt"]).contains("€ œ"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repeated_runs_are_byte_identical() {
+        // Created out of order, with many same-sized files for the size sort to tie on
+        let names: Vec<String> = (0..40).rev().map(|i| format!("d{}/f{:02}.{}", i % 4, i, ["rs", "md", "txt"][i % 3])).collect();
+        let dir = fixture("deterministic", &names.iter().map(String::as_str).collect::<Vec<_>>());
+        for args in [
+            &["-r", "*"][..],
+            &["--format", "json", "--hash", "-r", "*"],
+            &["--group-by-ext", "--sort", "size", "-r", "*"],
+            &["--tree", "--sort", "name", "--toc", "-r", "*"],
+        ] {
+            let first = run_bytes(&dir, args);
+            assert_eq!(first, run_bytes(&dir, args), "{:?}", args);
+            assert_eq!(first, run_bytes(&dir, args), "{:?}", args);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn abs_patterns_match_the_absolute_path() {
+        let dir = fixture("abs-patterns", &["src/main.rs", "lib/main.rs"]);
+        let absolute = format!("{}/src/*.rs", match_subject(&dir));
+        let none = Vec::<String>::new();
+        assert_eq!(selected(&dir, &["-r", "src/*.rs"]), ["src/main.rs"]);
+        assert_eq!(selected(&dir, &["-r", &absolute]), none);
+
+        assert_eq!(selected(&dir, &["--abs-patterns", "-r", &absolute]), ["src/main.rs"]);
+        assert_eq!(selected(&dir, &["--abs-patterns", "-r", "src/*.rs"]), none);
+        // A leading `**/` matches in both modes
+        assert_eq!(selected(&dir, &["--abs-patterns", "-r", "**/src/*.rs"]), ["src/main.rs"]);
+        assert_eq!(selected(&dir, &["-r", "**/src/*.rs"]), ["src/main.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn context_header_describes_the_checkout() {
+        let dir = fixture("context", &["src/main.rs"]);
+        let name = dir.file_name().unwrap().to_str().unwrap().to_string();
+        let output = run(&dir, &["--context-header", "-r", "*.rs"]);
+        let lines: Vec<&str> = output.lines().take_while(|line| !line.is_empty()).collect();
+        // Outside a repository there's no branch or commit
+        assert_eq!(lines.len(), 2, "{}", output);
+        assert_eq!(lines[0], format!("# Repository: {}", name));
+        assert!(lines[1].starts_with("# Generated: 20") && lines[1].ends_with('Z'), "{}", lines[1]);
+
+        if !crate::git::git_available() {
+            fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        let git = |args: &[&str]| {
+            let output = std::process::Command::new("git").arg("-C").arg(&dir).args(args).output().unwrap();
+            assert!(output.status.success(), "git {:?}", args);
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        git(&["init", "-q", "-b", "feature"]);
+        git(&["add", "."]);
+        git(&["-c", "user.name=test", "-c", "user.email=test@example.com", "commit", "-q", "-m", "initial"]);
+        let commit = git(&["rev-parse", "HEAD"]);
+
+        let output = run(&dir.join("src"), &["--context-header", "*.rs"]);
+        let lines: Vec<&str> = output.lines().take_while(|line| !line.is_empty()).collect();
+        assert_eq!(lines[..3], [format!("# Repository: {}", name), "# Branch: feature".to_string(), format!("# Commit: {}", commit)]);
+        assert!(lines[3].starts_with("# Generated: "), "{}", output);
+        assert!(output.contains("# File: ./main.rs"), "{}", output);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tracked_only_intersects_with_the_patterns() {
+        let dir = fixture("tracked-only", &["a.rs", "b.rs", "notes.md", "build/out.rs"]);
+        let mut args = CliArgs::parse_from(["agg-files", "--tracked-only", "-r", "*.rs"].map(String::from)).unwrap();
+        let tracked = ["a.rs", "notes.md", "build/out.rs", "gone.rs"].map(PathBuf::from).to_vec();
+        args.restrict_to_tracked(&dir, tracked);
+        let processor = FileProcessor::without_output(args, dir.clone(), Config::default()).unwrap();
+        let found: Vec<String> = processor
+            .ordered_files()
+            .into_iter()
+            .map(|(path, _)| match_subject(&processor.relative_path(&path)))
+            .collect();
+        assert_eq!(found, ["a.rs", "build/out.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wrap_is_opt_in() {
+        let dir = fixture("wrap-lines", &["long.txt"]);
+        fs::write(dir.join("long.txt"), "0123456789abc\n").unwrap();
+        assert!(run(&dir, &["long.txt"]).contains("\n0123456789abc\n"));
+        assert!(run(&dir, &["--wrap", "13", "long.txt"]).contains("\n0123456789abc\n"));
+        assert!(run(&dir, &["--wrap", "10", "long.txt"]).contains("\n0123456789\n↪ abc\n"));
+        assert!(run(&dir, &["--wrap", "10", "-n", "long.txt"]).contains("\n1 | 012345\n    ↪ 6789\n    ↪ abc\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn aggregate_sources_shares_one_output_in_order() {
+        let first = fixture("source-one", &["src/a.rs", "README.md"]);
+        let second = fixture("source-two", &["lib.rs", "docs/guide.md"]);
+        fs::write(first.join("intro.txt"), "Intro\n").unwrap();
+        fs::write(first.join("outro.txt"), "Outro\n").unwrap();
+        let urls = ["https://github.com/org/one", "https://github.com/org/two/tree/main/crates"];
+        let out = first.with_extension("out");
+        let (intro, outro) = (first.join("intro.txt"), first.join("outro.txt"));
+        let argv = [
+            "agg-files", "-o", out.to_str().unwrap(), "--url", urls[0], "--url", urls[1], "-r", "*.rs",
+            "--prepend", intro.to_str().unwrap(), "--append", outro.to_str().unwrap(),
+        ];
+        let args = CliArgs::parse_from(argv.map(String::from)).unwrap();
+
+        let failed = FileProcessor::aggregate_sources(args, &[first.clone(), second.clone()], Config::default());
+        assert_eq!(failed.unwrap(), 0);
+        let written = fs::read_to_string(&out).unwrap();
+        let sections: Vec<&str> = written
+            .lines()
+            .filter(|line| ["# Source:", "# File:", "Intro", "Outro"].iter().any(|start| line.starts_with(start)))
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                "# Source: https://github.com/org/one",
+                "Intro",
+                "# File: ./src/a.rs",
+                "# Source: https://github.com/org/two/tree/main/crates",
+                "# File: ./lib.rs",
+                "Outro",
+            ]
+        );
+        fs::remove_file(&out).unwrap();
+        fs::remove_dir_all(&first).unwrap();
+        fs::remove_dir_all(&second).unwrap();
+    }
+
+    #[test]
+    fn max_lines_keeps_files_at_the_limit() {
+        let dir = fixture("max-lines", &["four.txt", "open.txt", "three.txt"]);
+        fs::write(dir.join("four.txt"), "a\nb\nc\nd\n").unwrap();
+        fs::write(dir.join("open.txt"), "a\nb\nc").unwrap();
+        fs::write(dir.join("three.txt"), "a\nb\nc\n").unwrap();
+        let headers = |output: &str| output.lines().filter(|line| line.starts_with("# File:")).count();
+
+        let output = run(&dir, &["--max-lines", "3", "*.txt"]);
+        assert_eq!(headers(&output), 2, "{}", output);
+        assert!(output.starts_with("# Skipped (too many lines): ./four.txt (4 lines)\n"), "{}", output);
+        assert_eq!(headers(&run(&dir, &["--max-lines", "4", "*.txt"])), 3);
+        assert_eq!(headers(&run(&dir, &["--max-lines", "2", "*.txt"])), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counted_lines_match_the_read_contents() {
+        let dir = fixture("count-lines", &[]);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lines.txt");
+        for contents in ["", "a", "a\n", "a\nb", "a\r\nb\r\n", "\n\n"] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(count_lines(&path).unwrap(), contents.split_inclusive('\n').count(), "{:?}", contents);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn template_dir_renders_the_whole_output() {
+        let dir = fixture("template-dir", &["a.rs", "src/b.txt"]);
+        let templates = dir.with_extension("templates");
+        let _ = fs::remove_dir_all(&templates);
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("header.tmpl"), "<files>\n").unwrap();
+        fs::write(templates.join("file.tmpl"), "<file path=\"{path}\" size=\"{size}\" ext=\"{ext}\">{content}</file>\n").unwrap();
+        fs::write(templates.join("footer.tmpl"), "</files>\n").unwrap();
+
+        let output = run(&dir, &["--template-dir", templates.to_str().unwrap(), "-r", "*"]);
+        let expected = "\
+<files>
+<file path=\"./a.rs\" size=\"4\" ext=\"rs\">a.rs</file>
+<file path=\"./src/b.txt\" size=\"9\" ext=\"txt\">src/b.txt</file>
+</files>
+";
+        assert_eq!(output, expected);
+        fs::remove_dir_all(&templates).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export_ignored_files_are_skipped_on_request() {
+        let dir = fixture("export-ignore", &["src/main.rs", "tests/it.rs", "bench.rs", "docs/keep.rs"]);
+        fs::write(dir.join(".gitattributes"), "tests/ export-ignore\nbench.rs export-ignore\n*.rs text\n").unwrap();
+        fs::write(dir.join("docs/.gitattributes"), "*.rs export-ignore\n").unwrap();
+        assert_eq!(selected(&dir, &["--respect-export-ignore", "-r", "*.rs"]), ["src/main.rs"]);
+        assert_eq!(selected(&dir, &["-r", "*.rs"]), ["bench.rs", "docs/keep.rs", "src/main.rs", "tests/it.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn excluded_dirs_are_never_walked() {
+        let files = ["src/a.js", "node_modules/pkg/index.js", "node_modules/pkg/deep/x.js", "web/node_modules/y.js"];
+        let dir = fixture("exclude-dir", &files);
+        fs::create_dir_all(dir.join("vendor/lib")).unwrap();
+        // Walked directories are only recorded for --emit-empty-dirs
+        let args = ["--dry-run", "--emit-empty-dirs", "--exclude-dir", "node_modules", "--exclude-dir", "vendor/"];
+        let processor = processor(&dir, &[&args[..], &["-r", "*.js"]].concat());
+        let relative = |path: &Path| match_subject(&processor.relative_path(path));
+        let found: Vec<String> = processor.ordered_files().iter().map(|(path, _)| relative(path)).collect();
+        assert_eq!(found, ["src/a.js"]);
+        let walked: Vec<String> = processor.walked_dirs.borrow().iter().map(|dir| relative(dir)).collect();
+        assert_eq!(walked, ["src", "web"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn modified_since_drops_older_files() {
+        let dir = fixture("modified-since", &["new.txt", "old.txt"]);
+        let two_days_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 60 * 60);
+        File::options().write(true).open(dir.join("old.txt")).unwrap().set_modified(two_days_ago).unwrap();
+        assert_eq!(selected(&dir, &["--modified-since", "1d", "*.txt"]), ["new.txt"]);
+        assert_eq!(selected(&dir, &["--modified-since", "3d", "*.txt"]), ["new.txt", "old.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn token_summary_matches_the_per_file_counts() {
+        let dir = fixture("count-tokens", &["a.txt", "src/lib.rs", "notes.md"]);
+        fs::write(dir.join("a.txt"), "Some words, and a few more.\n").unwrap();
+        let out = dir.with_extension("out");
+        let argv = ["agg-files", "-o", out.to_str().unwrap(), "-r", "--files-only", "--count-tokens", "*"];
+        let args = CliArgs::parse_from(argv.map(String::from)).unwrap();
+        let output = Output::create(&args).unwrap();
+        let processor = FileProcessor::new(args, dir.clone(), output, Config::default()).unwrap();
+        processor.process().unwrap();
+
+        // What the "Approximate tokens" line reports
+        let total = processor.stats.borrow().tokens;
+        let listed = fs::read_to_string(&out).unwrap();
+        let per_file: Vec<usize> = listed
+            .lines()
+            .filter_map(|line| line.split_once("(~")?.1.strip_suffix(" tokens)")?.parse().ok())
+            .collect();
+        assert_eq!(per_file.len(), 3, "{}", listed);
+        assert_eq!(per_file.iter().sum::<usize>(), total);
+        let contents = ["Some words, and a few more.\n", "src/lib.rs", "notes.md"];
+        assert_eq!(total, contents.map(estimate_tokens).iter().sum::<usize>());
+        fs::remove_file(&out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn min_file_size_keeps_files_at_the_limit() {
+        let dir = fixture("min-size", &["a.txt", "bb.txt", ".gitkeep"]);
+        fs::write(dir.join(".gitkeep"), "").unwrap();
+        assert_eq!(selected(&dir, &["--include-hidden", "--min-file-size", "1", "*"]), ["a.txt", "bb.txt"]);
+        assert_eq!(selected(&dir, &["--min-file-size", "6", "*"]), ["bb.txt"]);
+        assert_eq!(selected(&dir, &["--min-file-size", "7", "*"]), Vec::<String>::new());
+        assert_eq!(selected(&dir, &["--min-file-size", "0.001k", "*"]), ["a.txt", "bb.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gzip_output_decompresses_to_the_plain_output() {
+        let dir = fixture("gzip", &["src/main.rs", "README.md"]);
+        let plain = run(&dir, &["-r", "*"]);
+        let compressed = run_bytes(&dir, &["--gzip", "-r", "*"]);
+        assert_eq!(&compressed[..2], [0x1f, 0x8b]);
+        let mut decompressed = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(compressed.as_slice()), &mut decompressed).unwrap();
+        assert_eq!(decompressed, plain);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_lists_exactly_the_emitted_files() {
+        let dir = fixture("manifest", &["src/main.rs", "notes.txt", "image.png"]);
+        fs::write(dir.join("image.png"), b"\x89PNG\0\0").unwrap();
+        fs::write(dir.join("notes.txt"), "one\ntwo\n").unwrap();
+        let manifest = dir.with_extension("json");
+        let output = run(&dir, &["--manifest", manifest.to_str().unwrap(), "-r", "*"]);
+
+        let entries: Vec<ManifestEntry> = serde_json::from_str(&fs::read_to_string(&manifest).unwrap()).unwrap();
+        let headers: Vec<&str> = output.lines().filter_map(|line| line.strip_prefix("# File: ")).collect();
+        assert_eq!(entries.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(), headers);
+        assert_eq!(headers, ["./notes.txt", "./src/main.rs"]);
+        let notes = &entries[0];
+        assert_eq!((notes.size, notes.lines), (8, 2));
+        assert_eq!(notes.sha256, sha256_hex(b"one\ntwo\n"));
+        fs::remove_file(&manifest).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_adds_the_sha256_of_the_raw_bytes() {
+        let dir = fixture("hash", &["abc.txt"]);
+        fs::write(dir.join("abc.txt"), "abc").unwrap();
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256_hex(b"abc"), digest);
+        let output = run(&dir, &["--hash", "abc.txt"]);
+        assert!(output.starts_with(&format!("# File: ./abc.txt (sha256: {})\nabc\n", digest)), "{}", output);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn comment_headers_wrap_the_header_in_a_comment() {
+        let dir = fixture("comment-headers", &["main.rs", "index.html", "run.py"]);
+        let output = run(&dir, &["--comment-headers", "*"]);
+        let headers: Vec<&str> = output.lines().filter(|line| line.contains("File: ")).collect();
+        assert_eq!(headers, ["<!-- File: ./index.html -->", "// File: ./main.rs", "# File: ./run.py"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn match_basename_tests_only_the_file_name() {
+        let dir = fixture("basename", &["test.rs", "src/test.rs", "tests/a.rs"]);
+        assert_eq!(selected(&dir, &["-r", "test**"]), ["src/test.rs", "test.rs", "tests/a.rs"]);
+        assert_eq!(selected(&dir, &["-r", "--match-basename", "test**"]), ["src/test.rs", "test.rs"]);
+        // A pattern with a `/` still names a path
+        assert_eq!(selected(&dir, &["-r", "--match-basename", "src/test.rs"]), ["src/test.rs"]);
+        assert_eq!(selected(&dir, &["-r", "--match-basename", "t*.rs"]), ["src/test.rs", "test.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn color_is_only_used_in_a_terminal() {
+        let dir = fixture("color", &["a.rs"]);
+        assert!(!run(&dir, &["a.rs"]).contains('\x1b'));
+        assert!(!processor(&dir, &["--no-color", "a.rs"]).color);
+        assert!(!processor(&dir, &["--gzip", "a.rs"]).color);
+        let painted = FileProcessor { color: true, ..processor(&dir, &["a.rs"]) };
+        assert!(painted.paint_header("# File: ./a.rs").contains('\x1b'));
+        assert_eq!(painted.paint_separator("==\n\n==").matches('\n').count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn count_only_totals_the_selected_files() {
+        let dir = fixture("count-only", &["a.txt", "src/b.rs", "image.bin"]);
+        fs::write(dir.join("a.txt"), "one\ntwo\nthree").unwrap();
+        fs::write(dir.join("src/b.rs"), "fn b() {}\n").unwrap();
+        fs::write(dir.join("image.bin"), b"\0\x01\x02").unwrap();
+        assert_eq!(run(&dir, &["--count-only", "-r", "*.txt", "*.rs"]), "2 files, 23 bytes, 4 lines\n");
+        assert_eq!(run(&dir, &["--count-only", "*.rs"]), "0 files, 0 bytes, 0 lines\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_matched_lossily() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = fixture("non-utf8", &["plain.txt"]);
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        fs::write(dir.join(name), "latin-1 name").unwrap();
+        assert_eq!(selected(&dir, &["caf*.txt"]), ["caf\u{fffd}.txt"]);
+        assert_eq!(selected(&dir, &["caf\u{fffd}.txt"]), ["caf\u{fffd}.txt"]);
+        assert_eq!(selected(&dir, &["*.txt"]).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flatten_numbers_repeated_names() {
+        let dir = fixture("flatten", &["a/main.rs", "b/main.rs", "c/lib.rs"]);
+        let output = run(&dir, &["--flatten", "--tree", "-r", "*.rs"]);
+        let headers: Vec<&str> = output.lines().filter(|line| line.starts_with("# File:")).collect();
+        assert_eq!(headers, ["# File: main.rs", "# File: main.rs (2)", "# File: lib.rs"]);
+        assert!(output.contains("main.rs (2)\n"), "{}", output);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn large_files_are_streamed_intact() {
+        let dir = fixture("stream", &["small.txt"]);
+        fs::write(dir.join("small.txt"), "s\n").unwrap();
+        let line = "0123456789abcdefghijklmnopqrstuvwxyz\u{e9}\n";
+        let big = line.repeat(STREAM_THRESHOLD as usize / line.len() + 1000);
+        fs::write(dir.join("big.txt"), &big).unwrap();
+        assert!(should_stream(&processor(&dir, &[]).args, big.len() as u64));
+
+        let small = run(&dir, &["small.txt"]);
+        let footer = small.strip_prefix("# File: ./small.txt\ns\n").unwrap();
+        assert_eq!(run(&dir, &["big.txt"]), format!("# File: ./big.txt\n{}{}", big, footer));
+
+        let numbered = run(&dir, &["-n", "--tail", "1", "big.txt"]);
+        let last = big.lines().count();
+        assert!(numbered.contains(&format!("{} | {}", last, line)), "{}", &numbered[..200]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dotfiles_are_skipped_unless_included() {
+        let dir = fixture("hidden", &[".env", ".github/ci.yml", "main.rs"]);
+        assert_eq!(selected(&dir, &["-r", "*"]), ["main.rs"]);
+        assert_eq!(selected(&dir, &["-r", "--include-hidden", "*"]), [".env", ".github/ci.yml", "main.rs"]);
+        // Named outright, a dotfile is always read
+        assert_eq!(selected(&dir, &[".env"]), [".env"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn progress_leaves_the_output_alone() {
+        let dir = fixture("progress", &["a.rs", "src/b.rs", "src/c.md"]);
+        let plain = run(&dir, &["-r", "*"]);
+        assert_eq!(run(&dir, &["--progress", "-r", "*"]), plain);
+        assert_eq!(run(&dir, &["--progress", "--format", "json", "-r", "*"]), run(&dir, &["--format", "json", "-r", "*"]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn negated_patterns_exclude_what_others_match() {
+        let dir = fixture("negated", &["Cargo.lock", "Cargo.toml", "src/main.rs", "src/gen.rs", "!notes.md"]);
+        assert_eq!(
+            selected(&dir, &["-r", "Cargo.*", "*.rs", "!*.lock", "!gen.rs"]),
+            ["Cargo.toml", "src/main.rs"]
+        );
+        assert_eq!(selected(&dir, &["\\!notes.md", "!*.toml"]), ["!notes.md"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn double_star_recurses_without_r_and_stays_anchored() {
+        let dir = fixture("double-star", &["src/a.rs", "src/deep/b.rs", "x/src/c.rs", "Cargo.toml", "x/d.toml"]);
+        assert_eq!(selected(&dir, &["src/**", "*.toml"]), ["src/a.rs", "src/deep/b.rs", "Cargo.toml"]);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
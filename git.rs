@@ -0,0 +1,193 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The global excludes file git would read for `dir`: `core.excludesFile`
+/// from any git config (with `~` expanded), or git's default
+/// `$XDG_CONFIG_HOME/git/ignore` when that isn't set. Without a `git`
+/// binary the global `~/.gitconfig` is read directly. The file may not exist.
+pub fn excludes_file(dir: &Path) -> Option<PathBuf> {
+    git_output(dir, &["config", "--path", "core.excludesFile"])
+        .map(PathBuf::from)
+        .or_else(ignore::gitignore::gitconfig_excludes_path)
+}
+
+/// Git details for `--context-header`.
+pub struct RepoContext {
+    /// The repository's top-level directory
+    pub root: PathBuf,
+    /// `None` on a detached HEAD
+    pub branch: Option<String>,
+    /// `None` before the first commit
+    pub commit: Option<String>,
+}
+
+/// Describes the repository containing `dir`, or `None` outside one (or
+/// when git isn't installed).
+pub fn repo_context(dir: &Path) -> Option<RepoContext> {
+    let root = git_output(dir, &["rev-parse", "--show-toplevel"])?;
+    Some(RepoContext {
+        root: PathBuf::from(root),
+        branch: git_output(dir, &["symbolic-ref", "--short", "-q", "HEAD"]),
+        commit: git_output(dir, &["rev-parse", "--verify", "-q", "HEAD"]),
+    })
+}
+
+/// Runs git in `dir` and returns what it printed, trimmed, or `None` if it
+/// couldn't run, failed or printed nothing.
+fn git_output(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output().ok()?;
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !text.is_empty()).then_some(text)
+}
+
+/// Runs git in `dir` and returns its output, or what it said went wrong.
+fn run_git(dir: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| format!("failed to run git: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr.trim().trim_start_matches("fatal: ").to_string());
+    }
+    Ok(output.stdout)
+}
+
+/// Lists the files that differ from `git_ref` (committed or not), relative
+/// to `dir`. Deleted files are left out since there's nothing to read.
+pub fn changed_files(dir: &Path, git_ref: &str) -> Result<Vec<PathBuf>, String> {
+    // Outside a repository `git diff` falls back to comparing two paths, so check first
+    require_work_tree(dir)?;
+
+    let output = run_git(dir, &["diff", "--name-only", "--relative", "--diff-filter=d", git_ref, "--"])?;
+    Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Lists the files git tracks under `dir`, relative to it. Tracked files
+/// that have since been deleted from the work tree are included.
+pub fn tracked_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    require_work_tree(dir)?;
+
+    // NUL-separated so unusual names aren't quoted
+    let output = run_git(dir, &["ls-files", "-z", "--"])?;
+    Ok(String::from_utf8_lossy(&output)
+        .split('\0')
+        .filter(|name| !name.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Whether a `git` binary can be run, so tests that need one can skip
+/// without it.
+#[cfg(test)]
+pub(crate) fn git_available() -> bool {
+    let available = Command::new("git").arg("--version").output().is_ok_and(|output| output.status.success());
+    if !available {
+        eprintln!("git can't be run, skipping");
+    }
+    available
+}
+
+fn require_work_tree(dir: &Path) -> Result<(), String> {
+    let inside = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .map_err(|e| format!("failed to run git: {}", e))?;
+    if !inside.status.success() {
+        return Err(format!("{} is not inside a git repository", dir.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A new repository with `files` committed, each containing its own name.
+    fn repo(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agg-files-git-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        git(&dir, &["init", "-q", "-b", "main"]);
+        for file in files {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        git(&dir, &["add", "."]);
+        git(&dir, &["-c", "user.name=test", "-c", "user.email=test@example.com", "commit", "-q", "-m", "initial"]);
+        dir
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git").arg("-C").arg(dir).args(args).status().unwrap();
+        assert!(status.success(), "git {:?}", args);
+    }
+
+    #[test]
+    fn changed_files_lists_edits_and_additions_but_not_deletions() {
+        if !git_available() {
+            return;
+        }
+        let dir = repo("changed", &["kept.rs", "edited.rs", "deleted.rs"]);
+        fs::write(dir.join("edited.rs"), "changed").unwrap();
+        fs::remove_file(dir.join("deleted.rs")).unwrap();
+        fs::write(dir.join("added.rs"), "new").unwrap();
+        git(&dir, &["add", "added.rs"]);
+
+        let mut changed = changed_files(&dir, "HEAD").unwrap();
+        changed.sort();
+        assert_eq!(changed, [PathBuf::from("added.rs"), PathBuf::from("edited.rs")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_files_reports_git_errors() {
+        if !git_available() {
+            return;
+        }
+        let dir = repo("bad-ref", &["a.rs"]);
+        assert!(changed_files(&dir, "no-such-ref").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let outside = std::env::temp_dir().join(format!("agg-files-no-repo-{}", std::process::id()));
+        fs::create_dir_all(&outside).unwrap();
+        let error = changed_files(&outside, "HEAD").err().unwrap();
+        assert!(error.ends_with("is not inside a git repository"), "{}", error);
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn tracked_files_leaves_out_untracked_ones() {
+        if !git_available() {
+            return;
+        }
+        let dir = repo("tracked", &["a.rs", "deleted.rs"]);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/b.rs"), "b").unwrap();
+        git(&dir, &["add", "sub/b.rs"]);
+        fs::write(dir.join("untracked.rs"), "new").unwrap();
+        fs::write(dir.join("sub/untracked.rs"), "new").unwrap();
+        fs::remove_file(dir.join("deleted.rs")).unwrap();
+
+        let tracked = tracked_files(&dir).unwrap();
+        assert_eq!(tracked, [PathBuf::from("a.rs"), PathBuf::from("deleted.rs"), PathBuf::from("sub/b.rs")]);
+        // Relative to the directory asked about
+        assert_eq!(tracked_files(&dir.join("sub")).unwrap(), [PathBuf::from("b.rs")]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let outside = std::env::temp_dir().join(format!("agg-files-untracked-{}", std::process::id()));
+        fs::create_dir_all(&outside).unwrap();
+        let error = tracked_files(&outside).err().unwrap();
+        assert!(error.ends_with("is not inside a git repository"), "{}", error);
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Applies the `export-ignore` attribute from `.gitattributes` files, for
+/// `--respect-export-ignore`: paths git would leave out of a `git archive`.
+/// As with attributes in git, every directory between the root and a path
+/// may have a `.gitattributes`, later lines override earlier ones and a
+/// deeper file overrides its parents. `-export-ignore` and `!export-ignore`
+/// clear the attribute again. A directory marked `export-ignore` leaves out
+/// everything in it.
+pub struct ExportIgnore {
+    root: PathBuf,
+    /// Whether warnings about invalid patterns are silenced
+    quiet: bool,
+    cache: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+impl ExportIgnore {
+    pub fn new(root: &Path, quiet: bool) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            quiet,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Describes the `.gitattributes` line that marks `path` export-ignore,
+    /// or `None` if it isn't.
+    pub fn ignored_by(&self, path: &Path, is_dir: bool) -> Option<String> {
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(&self.root) {
+                break;
+            }
+
+            let mut cache = self.cache.borrow_mut();
+            let attributes = cache.entry(dir.to_path_buf()).or_insert_with(|| build(dir, self.quiet));
+            if let Some(attributes) = attributes {
+                match attributes.matched(path, is_dir) {
+                    Match::Ignore(glob) => {
+                        let source = glob.from().map_or_else(|| dir.join(".gitattributes"), Path::to_path_buf);
+                        return Some(format!("export-ignore by '{}' in {}", glob.original(), source.display()));
+                    }
+                    Match::Whitelist(_) => return None,
+                    Match::None => {}
+                }
+            }
+
+            if dir == self.root {
+                break;
+            }
+        }
+        None
+    }
+}
+
+/// Turns the `export-ignore` settings in `dir/.gitattributes` into ignore
+/// rules: set becomes a rule, unset a `!` whitelist. Attribute patterns have
+/// the same syntax as `.gitignore` ones, except that they can't be negated,
+/// so lines starting with `!` are skipped like git skips them. An invalid
+/// pattern is warned about unless `quiet`.
+fn build(dir: &Path, quiet: bool) -> Option<Gitignore> {
+    let path = dir.join(".gitattributes");
+    let contents = fs::read_to_string(&path).ok()?;
+
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(pattern) = fields.next().filter(|pattern| !pattern.starts_with('#') && !pattern.starts_with('!'))
+        else {
+            continue;
+        };
+        // The last mention on a line wins, as git reads them in order
+        let Some(set) = fields.filter_map(export_ignore_setting).next_back() else {
+            continue;
+        };
+        let rule = if set { pattern.to_string() } else { format!("!{}", pattern) };
+        if let Err(e) = builder.add_line(Some(path.clone()), &rule) {
+            if !quiet {
+                eprintln!("Warning: error reading {}: {}", path.display(), e);
+            }
+        }
+        found = true;
+    }
+
+    if found {
+        builder.build().ok()
+    } else {
+        None
+    }
+}
+
+/// Whether an attribute sets (`export-ignore`) or clears (`-export-ignore`,
+/// `!export-ignore`) export-ignore, or `None` for any other attribute.
+fn export_ignore_setting(attribute: &str) -> Option<bool> {
+    match attribute {
+        "export-ignore" => Some(true),
+        "-export-ignore" | "!export-ignore" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_ignore_can_be_set_and_cleared() {
+        let dir = std::env::temp_dir().join(format!("agg-files-gitattributes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join(".gitattributes"), "*.md export-ignore\ntests/ export-ignore\n!x.md export-ignore\n").unwrap();
+        fs::write(dir.join("docs/.gitattributes"), "keep.md -export-ignore\nlast.md export-ignore -export-ignore\n").unwrap();
+        let attributes = ExportIgnore::new(&dir, true);
+
+        let reason = attributes.ignored_by(&dir.join("README.md"), false).unwrap();
+        assert!(reason.starts_with("export-ignore by '*.md' in "), "{}", reason);
+        assert!(attributes.ignored_by(&dir.join("tests"), true).is_some());
+        assert!(attributes.ignored_by(&dir.join("docs/guide.md"), false).is_some());
+        assert!(attributes.ignored_by(&dir.join("docs/keep.md"), false).is_none());
+        assert!(attributes.ignored_by(&dir.join("docs/last.md"), false).is_none());
+        assert!(attributes.ignored_by(&dir.join("src/main.rs"), false).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export_ignore_setting_reads_only_that_attribute() {
+        assert_eq!(export_ignore_setting("export-ignore"), Some(true));
+        assert_eq!(export_ignore_setting("-export-ignore"), Some(false));
+        assert_eq!(export_ignore_setting("!export-ignore"), Some(false));
+        assert_eq!(export_ignore_setting("text"), None);
+    }
+}
@@ -1,10 +1,14 @@
-use reqwest;
 use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
 use tokio::fs;
 use flate2::read::GzDecoder;
 use tar::Archive;
 use url::Url;
 
+/// How many times a request is tried before a network failure is reported
+const ATTEMPTS: u32 = 3;
+
 pub struct RepoInfo {
     pub owner: String,
     pub repo: String,
@@ -16,6 +20,12 @@ pub struct GitHubHandler {
     client: reqwest::Client,
 }
 
+impl Default for GitHubHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GitHubHandler {
     pub fn new() -> Self {
         Self {
@@ -25,27 +35,41 @@ impl GitHubHandler {
 
     pub fn parse_url(&self, url: &str) -> Result<RepoInfo, Box<dyn Error>> {
         let parsed_url = Url::parse(url)?;
+        match parsed_url.host_str() {
+            Some("github.com") | Some("www.github.com") => {}
+            _ => return Err(format!("Not a GitHub URL: {}", url).into()),
+        }
+
         let path_segments: Vec<&str> = parsed_url.path_segments()
             .ok_or("Invalid URL")?
+            .filter(|segment| !segment.is_empty())
             .collect();
 
         if path_segments.len() < 2 {
-            return Err("Invalid GitHub URL".into());
+            return Err("Invalid GitHub URL: expected https://github.com/<owner>/<repo>".into());
         }
 
         let owner = path_segments[0].to_string();
-        let repo = path_segments[1].to_string();
-        
-        let (branch, path) = if path_segments.len() > 3 && path_segments[2] == "tree" {
-            let branch = path_segments[3].to_string();
-            let path = if path_segments.len() > 4 {
-                Some(path_segments[4..].join("/"))
-            } else {
-                None
-            };
-            (branch, path)
-        } else {
-            ("main".to_string(), None)
+        let repo = path_segments[1].trim_end_matches(".git").to_string();
+
+        let (branch, path) = match path_segments.get(2) {
+            None => ("main".to_string(), None),
+            Some(&"tree") if path_segments.len() > 3 => {
+                let branch = path_segments[3].to_string();
+                let path = if path_segments.len() > 4 {
+                    Some(path_segments[4..].join("/"))
+                } else {
+                    None
+                };
+                (branch, path)
+            }
+            Some(_) => {
+                return Err(format!(
+                    "Unsupported GitHub URL: {} (expected https://github.com/<owner>/<repo>/tree/<branch>/<path>)",
+                    url
+                )
+                .into())
+            }
         };
 
         Ok(RepoInfo {
@@ -56,62 +80,121 @@ impl GitHubHandler {
         })
     }
 
-    pub async fn download_repository(&self, repo_info: &RepoInfo) -> Result<(), Box<dyn Error>> {
+    /// Looks up the commit the branch currently points at.
+    pub async fn resolve_commit(&self, repo_info: &RepoInfo) -> Result<String, Box<dyn Error>> {
+        let url = format!(
+            "https://api.github.com/repos/{}/{}/commits/{}",
+            repo_info.owner, repo_info.repo, repo_info.branch
+        );
+        let response = self.get(&url, "application/vnd.github.sha").await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Failed to look up {}/{} at '{}': HTTP {}",
+                repo_info.owner, repo_info.repo, repo_info.branch, response.status()
+            )
+            .into());
+        }
+
+        let commit = response.text().await?.trim().to_string();
+        if commit.is_empty() || !commit.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("Unexpected commit id from GitHub: {}", commit).into());
+        }
+        Ok(commit)
+    }
+
+    /// Downloads the repository at `commit` into the cache.
+    pub async fn download_repository(&self, repo_info: &RepoInfo, commit: &str) -> Result<(), Box<dyn Error>> {
         let temp_manager = crate::temp_manager::TempManager::new();
-        let target_dir = temp_manager.get_repo_path(repo_info);
+        let target_dir = temp_manager.get_commit_path(repo_info, commit);
+
+        // Extract into a separate staging directory so a failed download never
+        // leaves a partial directory behind that later runs would treat as cached
+        let staging_dir = temp_manager.get_staging_path(repo_info, commit);
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir).await?;
+        }
+        fs::create_dir_all(&staging_dir).await?;
+
+        let result = self.download_into(repo_info, commit, &staging_dir, &target_dir).await;
 
-        // Create target directory if it doesn't exist
-        fs::create_dir_all(&target_dir).await?;
+        // Clean up staging directory
+        let _ = fs::remove_dir_all(&staging_dir).await;
 
+        result
+    }
+
+    async fn download_into(
+        &self,
+        repo_info: &RepoInfo,
+        commit: &str,
+        staging_dir: &Path,
+        target_dir: &Path,
+    ) -> Result<(), Box<dyn Error>> {
         // Download tarball
         let url = format!(
             "https://api.github.com/repos/{}/{}/tarball/{}",
-            repo_info.owner, repo_info.repo, repo_info.branch
+            repo_info.owner, repo_info.repo, commit
         );
 
-        let response = self.client
-            .get(&url)
-            .header("User-Agent", "rust-file-finder")
-            .send()
-            .await?;
+        let response = self.get(&url, "application/vnd.github+json").await?;
+
+        if !response.status().is_success() {
+            return Err(format!(
+                "Failed to download {}/{} at '{}': HTTP {}",
+                repo_info.owner, repo_info.repo, repo_info.branch, response.status()
+            )
+            .into());
+        }
 
         let bytes = response.bytes().await?;
-        
+
         // Extract tarball
         let decoder = GzDecoder::new(&bytes[..]);
         let mut archive = Archive::new(decoder);
-        
-        // Use a temporary directory for extraction
-        let temp_dir = target_dir.join("temp");
-        fs::create_dir_all(&temp_dir).await?;
-        
-        // Extract files
-        archive.unpack(&temp_dir)?;
-
-        // Move files from the extracted directory to the target directory
-        let extracted_dir = std::fs::read_dir(&temp_dir)?
+        archive.unpack(staging_dir)?;
+
+        // GitHub wraps the archive in a single `<owner>-<repo>-<sha>` directory
+        let extracted_dir = std::fs::read_dir(staging_dir)?
             .next()
             .ok_or("No files extracted")??.path();
 
-        if let Some(path) = &repo_info.path {
-            let source_dir = extracted_dir.join(path);
-            if source_dir.exists() {
-                std::fs::rename(source_dir, &target_dir)?;
-            } else {
-                return Err(format!("Path '{}' not found in repository", path).into());
-            }
-        } else {
-            // Move all files from extracted directory to target directory
-            for entry in std::fs::read_dir(extracted_dir)? {
-                let entry = entry?;
-                let target_path = target_dir.join(entry.file_name());
-                std::fs::rename(entry.path(), target_path)?;
-            }
+        // The whole repository is kept so other paths in it can reuse the download
+        if let Some(parent) = target_dir.parent() {
+            fs::create_dir_all(parent).await?;
         }
-
-        // Clean up temporary directory
-        std::fs::remove_dir_all(temp_dir)?;
+        // Only replace a copy from an earlier download (`--refresh`) once the new one is complete
+        if target_dir.exists() {
+            fs::remove_dir_all(target_dir).await?;
+        }
+        fs::rename(extracted_dir, target_dir).await?;
 
         Ok(())
     }
+
+    /// Sends a GET request, retrying connection failures and server errors a
+    /// couple of times before giving up.
+    async fn get(&self, url: &str, accept: &str) -> Result<reqwest::Response, reqwest::Error> {
+        let mut delay = Duration::from_millis(500);
+        for _ in 1..ATTEMPTS {
+            let result = self.client
+                .get(url)
+                .header("User-Agent", "rust-file-finder")
+                .header("Accept", accept)
+                .send()
+                .await;
+            match result {
+                Ok(response) if !response.status().is_server_error() => return Ok(response),
+                _ => {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+        self.client
+            .get(url)
+            .header("User-Agent", "rust-file-finder")
+            .header("Accept", accept)
+            .send()
+            .await
+    }
 }
@@ -1,14 +1,191 @@
-use ignore::gitignore::{GitignoreBuilder, Gitignore};
+use ignore::gitignore::{Glob, GitignoreBuilder, Gitignore};
+use ignore::Match;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-pub struct GitignoreHelper;
+use crate::git;
+
+/// Ignore files read in each directory, lowest precedence first: within a
+/// directory, rules from a later file override earlier ones, so the
+/// tool-specific files win over `.gitignore`.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore", ".aggignore", ".agg-filesignore"];
+
+/// Applies `.gitignore` files the way git does: every directory between the
+/// root and a path may contribute rules, and a deeper file's rules override
+/// those of its parents. `.ignore`, `.aggignore` and `.agg-filesignore` are
+/// read alongside them, so files can be left out of aggregation without
+/// touching git. As in git, the user's global excludes file
+/// (`core.excludesFile`) applies with the lowest precedence.
+pub struct GitignoreHelper {
+    root: PathBuf,
+    /// Whether `.gitignore` itself is read; `-i` turns it off but keeps the
+    /// other ignore files
+    use_gitignore: bool,
+    /// Rules from the global excludes file, matched relative to `root`
+    global: Option<Gitignore>,
+    /// Whether warnings about unreadable ignore files are silenced
+    quiet: bool,
+    cache: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
 
 impl GitignoreHelper {
-    pub fn build() -> Option<Gitignore> {
-        let mut builder = GitignoreBuilder::new(".");
-        if builder.add(".gitignore").is_none() {
+    pub fn new(root: &Path, use_gitignore: bool, quiet: bool) -> Self {
+        let global = if use_gitignore { Self::build_global(root) } else { None };
+        Self {
+            root: root.to_path_buf(),
+            use_gitignore,
+            global,
+            quiet,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the global excludes file, if git names one and it exists. A
+    /// missing or unreadable file is skipped silently, as git does.
+    fn build_global(root: &Path) -> Option<Gitignore> {
+        let path = git::excludes_file(root).filter(|path| path.is_file())?;
+        let mut builder = GitignoreBuilder::new(root);
+        if builder.add(&path).is_some() {
+            return None;
+        }
+        builder.build().ok()
+    }
+
+    /// Describes the rule that ignores `path` (its pattern and the file it
+    /// came from), or `None` if the path isn't ignored.
+    pub fn ignored_by(&self, path: &Path, is_dir: bool) -> Option<String> {
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(&self.root) {
+                break;
+            }
+
+            let mut cache = self.cache.borrow_mut();
+            let gitignore = cache
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| self.build(dir));
+            if let Some(gi) = gitignore {
+                match gi.matched(path, is_dir) {
+                    Match::Ignore(glob) => return Some(describe(glob)),
+                    Match::Whitelist(_) => return None,
+                    Match::None => {}
+                }
+            }
+
+            if dir == self.root {
+                break;
+            }
+        }
+
+        match self.global.as_ref().filter(|_| path.starts_with(&self.root))?.matched(path, is_dir) {
+            Match::Ignore(glob) => Some(describe(glob)),
+            _ => None,
+        }
+    }
+
+    fn build(&self, dir: &Path) -> Option<Gitignore> {
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in IGNORE_FILES {
+            if *name == ".gitignore" && !self.use_gitignore {
+                continue;
+            }
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            if let Some(e) = builder.add(&path) {
+                if !self.quiet {
+                    eprintln!("Warning: error reading {}: {}", path.display(), e);
+                }
+            }
+            found = true;
+        }
+
+        if found {
             builder.build().ok()
         } else {
             None
         }
     }
 }
+
+/// Names an ignore rule and the file it came from, for `--verbose`.
+fn describe(glob: &Glob) -> String {
+    let source = glob.from().map(|from| from.display().to_string());
+    format!(
+        "matches '{}' in {}",
+        glob.original(),
+        source.as_deref().unwrap_or("an ignore file")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agg-files-gitignore-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn deeper_files_and_later_files_override() {
+        let dir = temp_dir("override");
+        fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(dir.join("sub/.gitignore"), "!keep.log\n").unwrap();
+        fs::write(dir.join(".aggignore"), "!root.log\nsecret.txt\n").unwrap();
+        let helper = GitignoreHelper::new(&dir, true, true);
+
+        let reason = helper.ignored_by(&dir.join("a.log"), false).unwrap();
+        assert!(reason.starts_with("matches '*.log' in "), "{}", reason);
+        assert!(helper.ignored_by(&dir.join("sub/keep.log"), false).is_none());
+        assert!(helper.ignored_by(&dir.join("root.log"), false).is_none());
+        assert!(helper.ignored_by(&dir.join("secret.txt"), false).is_some());
+        assert!(helper.ignored_by(&dir.join("a.txt"), false).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn without_gitignore_the_other_files_still_apply() {
+        let dir = temp_dir("no-gitignore");
+        fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(dir.join(".ignore"), "*.tmp\n").unwrap();
+        let helper = GitignoreHelper::new(&dir, false, true);
+
+        assert!(helper.ignored_by(&dir.join("a.log"), false).is_none());
+        assert!(helper.ignored_by(&dir.join("sub/a.tmp"), false).is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_excludes_file_from_git_config_applies_first() {
+        if !crate::git::git_available() {
+            return;
+        }
+        let dir = temp_dir("excludes-file");
+        let excludes = dir.with_extension("excludes");
+        fs::write(&excludes, "*.swp\n*.log\n").unwrap();
+        fs::write(dir.join(".gitignore"), "!keep.log\n").unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git").arg("-C").arg(&dir).args(args).status().unwrap();
+            assert!(status.success(), "git {:?}", args);
+        };
+        git(&["init", "-q"]);
+        git(&["config", "core.excludesFile", excludes.to_str().unwrap()]);
+
+        let helper = GitignoreHelper::new(&dir, true, true);
+        let reason = helper.ignored_by(&dir.join("sub/a.swp"), false).unwrap();
+        assert!(reason.contains("'*.swp'") && reason.contains(&excludes.display().to_string()), "{}", reason);
+        // Repository rules take precedence over the global file
+        assert!(helper.ignored_by(&dir.join("keep.log"), false).is_none());
+        assert!(helper.ignored_by(&dir.join("other.log"), false).is_some());
+        // `-i` turns it off along with `.gitignore`
+        assert!(GitignoreHelper::new(&dir, false, true).ignored_by(&dir.join("a.swp"), false).is_none());
+        fs::remove_file(&excludes).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The plain-output header used when no `--header-template` is given.
+pub const DEFAULT_TEMPLATE: &str = "# File: {path}";
+
+/// The default with `--comment-headers`, which wraps it in the file's own comment syntax.
+pub const COMMENT_TEMPLATE: &str = "File: {path}";
+
+enum Part {
+    Literal(String),
+    Path,
+    Size,
+    Lines,
+    Ext,
+    Content,
+}
+
+/// Placeholders a `--header-template` can use.
+const HEADER_PLACEHOLDERS: &[&str] = &["path", "size", "lines", "ext"];
+
+/// Placeholders a `--template-dir` `file.tmpl` can use.
+const FILE_PLACEHOLDERS: &[&str] = &["path", "size", "lines", "ext", "content"];
+
+/// A parsed `--header-template`. `{path}`, `{size}`, `{lines}` and `{ext}`
+/// are substituted per file; `{{` and `}}` produce literal braces.
+pub struct HeaderTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+    /// Which template it is, e.g. `header template`
+    template: String,
+    message: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid {}: {}", self.template, self.message)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// The values available to a header for one file.
+pub struct HeaderFields<'a> {
+    pub path: &'a str,
+    pub size: u64,
+    /// `None` when the contents weren't read (e.g. `--files-only`)
+    pub lines: Option<usize>,
+    pub ext: &'a str,
+    /// The processed contents, for a `--template-dir` file template
+    pub content: Option<&'a str>,
+}
+
+impl HeaderTemplate {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        Self::parse_with(template, HEADER_PLACEHOLDERS, "header template")
+    }
+
+    /// Parses `template` allowing only the placeholders in `allowed`; errors
+    /// name it as `what`.
+    fn parse_with(template: &str, allowed: &[&str], what: &str) -> Result<Self, TemplateError> {
+        let error = |message: String| TemplateError { template: what.to_string(), message };
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(error(format!("unclosed '{{{}'", name))),
+                        }
+                    }
+                    if !allowed.contains(&name.as_str()) {
+                        return Err(error(unknown_placeholder(&name, allowed)));
+                    }
+                    let placeholder = match name.as_str() {
+                        "path" => Part::Path,
+                        "size" => Part::Size,
+                        "lines" => Part::Lines,
+                        "ext" => Part::Ext,
+                        _ => Part::Content,
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(placeholder);
+                }
+                '}' => return Err(error("unmatched '}' (use '}}' for a literal brace)".to_string())),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    pub fn render(&self, fields: &HeaderFields) -> String {
+        let mut header = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => header.push_str(text),
+                Part::Path => header.push_str(fields.path),
+                Part::Size => header.push_str(&fields.size.to_string()),
+                Part::Lines => match fields.lines {
+                    Some(lines) => header.push_str(&lines.to_string()),
+                    None => header.push('?'),
+                },
+                Part::Ext => header.push_str(fields.ext),
+                Part::Content => header.push_str(fields.content.unwrap_or("")),
+            }
+        }
+        header
+    }
+
+    /// The text of a template without placeholders.
+    fn literal(&self) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.as_str(),
+                _ => "",
+            })
+            .collect()
+    }
+}
+
+fn unknown_placeholder(name: &str, allowed: &[&str]) -> String {
+    let mut names: Vec<String> = allowed.iter().map(|name| format!("{{{}}}", name)).collect();
+    let expected = match names.pop() {
+        None => return format!("unknown placeholder '{{{}}}' (this template takes none)", name),
+        Some(last) if names.is_empty() => last,
+        Some(last) => format!("{} or {}", names.join(", "), last),
+    };
+    format!("unknown placeholder '{{{}}}' (expected {})", name, expected)
+}
+
+/// The templates in a `--template-dir`: `file.tmpl`, rendered for each file
+/// in place of its header, contents and separator, and the optional
+/// `header.tmpl` and `footer.tmpl` written once before and after the files.
+/// Each is written exactly as rendered, newlines included.
+pub struct TemplateSet {
+    pub header: Option<String>,
+    pub file: HeaderTemplate,
+    pub footer: Option<String>,
+}
+
+impl TemplateSet {
+    /// Reads and checks the templates in `dir`. `file.tmpl` is required and
+    /// can use the header placeholders plus `{content}`; the header and footer
+    /// are plain text, with `{{`/`}}` for literal braces.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let read = |name: &str| -> Result<Option<(String, String)>, String> {
+            let path = dir.join(name);
+            match fs::read_to_string(&path) {
+                Ok(text) => Ok(Some((text, format!("template {}", path.display())))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(format!("Cannot read template {}: {}", path.display(), e)),
+            }
+        };
+        let literal = |template: Option<(String, String)>| -> Result<Option<String>, String> {
+            template
+                .map(|(text, what)| HeaderTemplate::parse_with(&text, &[], &what).map(|template| template.literal()))
+                .transpose()
+                .map_err(|e| e.to_string())
+        };
+
+        let (file, what) = read("file.tmpl")?
+            .ok_or_else(|| format!("--template-dir {} has no file.tmpl", dir.display()))?;
+        Ok(Self {
+            header: literal(read("header.tmpl")?)?,
+            file: HeaderTemplate::parse_with(&file, FILE_PLACEHOLDERS, &what).map_err(|e| e.to_string())?,
+            footer: literal(read("footer.tmpl")?)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> HeaderFields<'static> {
+        HeaderFields {
+            path: "./src/main.rs",
+            size: 120,
+            lines: Some(7),
+            ext: "rs",
+            content: None,
+        }
+    }
+
+    #[test]
+    fn placeholders_are_substituted() {
+        let template = HeaderTemplate::parse("== {path} ({size} bytes, {lines} lines, .{ext}) ==").unwrap();
+        assert_eq!(template.render(&fields()), "== ./src/main.rs (120 bytes, 7 lines, .rs) ==");
+        assert_eq!(HeaderTemplate::parse(DEFAULT_TEMPLATE).unwrap().render(&fields()), "# File: ./src/main.rs");
+    }
+
+    #[test]
+    fn unread_line_counts_show_as_a_question_mark() {
+        let template = HeaderTemplate::parse("{path}: {lines}").unwrap();
+        let fields = HeaderFields { lines: None, ..fields() };
+        assert_eq!(template.render(&fields), "./src/main.rs: ?");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let template = HeaderTemplate::parse("{{{path}}} {{}}").unwrap();
+        assert_eq!(template.render(&fields()), "{./src/main.rs} {}");
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        let error = |template: &str| HeaderTemplate::parse(template).err().unwrap().to_string();
+        assert_eq!(
+            error("{name}"),
+            "Invalid header template: unknown placeholder '{name}' (expected {path}, {size}, {lines} or {ext})"
+        );
+        assert!(error("{content}").contains("unknown placeholder '{content}'"));
+        assert_eq!(error("{path"), "Invalid header template: unclosed '{path'");
+        assert_eq!(error("a } b"), "Invalid header template: unmatched '}' (use '}}' for a literal brace)");
+    }
+
+    #[test]
+    fn template_sets_are_checked_when_loaded() {
+        let dir = std::env::temp_dir().join(format!("agg-files-templates-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let error = TemplateSet::load(&dir).err().unwrap();
+        assert_eq!(error, format!("--template-dir {} has no file.tmpl", dir.display()));
+
+        fs::write(dir.join("file.tmpl"), "<{path}|{lines}>\n{content}").unwrap();
+        fs::write(dir.join("header.tmpl"), "{{ begin }}\n").unwrap();
+        let templates = TemplateSet::load(&dir).unwrap();
+        assert_eq!(templates.header.as_deref(), Some("{ begin }\n"));
+        assert!(templates.footer.is_none());
+        let fields = HeaderFields { content: Some("fn main() {}\n"), ..fields() };
+        assert_eq!(templates.file.render(&fields), "<./src/main.rs|7>\nfn main() {}\n");
+
+        // Only the per-file template has placeholders
+        fs::write(dir.join("footer.tmpl"), "{path}\n").unwrap();
+        let error = TemplateSet::load(&dir).err().unwrap();
+        assert!(error.contains("footer.tmpl") && error.contains("unknown placeholder '{path}'"), "{}", error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::path::Path;
+
+/// Maps file extensions to the language identifiers used for markdown code fences.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("toml", "toml"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("json", "json"),
+    ("md", "markdown"),
+    ("js", "javascript"),
+    ("mjs", "javascript"),
+    ("cjs", "javascript"),
+    ("jsx", "jsx"),
+    ("ts", "typescript"),
+    ("tsx", "tsx"),
+    ("py", "python"),
+    ("rb", "ruby"),
+    ("go", "go"),
+    ("java", "java"),
+    ("kt", "kotlin"),
+    ("swift", "swift"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cpp", "cpp"),
+    ("cc", "cpp"),
+    ("hpp", "cpp"),
+    ("cs", "csharp"),
+    ("php", "php"),
+    ("sh", "bash"),
+    ("bash", "bash"),
+    ("zsh", "zsh"),
+    ("fish", "fish"),
+    ("ps1", "powershell"),
+    ("html", "html"),
+    ("htm", "html"),
+    ("css", "css"),
+    ("scss", "scss"),
+    ("xml", "xml"),
+    ("sql", "sql"),
+    ("lua", "lua"),
+    ("ex", "elixir"),
+    ("exs", "elixir"),
+    ("erl", "erlang"),
+    ("hs", "haskell"),
+    ("ml", "ocaml"),
+    ("scala", "scala"),
+    ("dart", "dart"),
+    ("vue", "vue"),
+    ("svelte", "svelte"),
+    ("dockerfile", "dockerfile"),
+    ("makefile", "makefile"),
+];
+
+/// Returns the code fence language for a path, or `None` for unknown extensions.
+pub fn language_for_path(path: &Path) -> Option<&'static str> {
+    // Files like `Dockerfile` and `Makefile` have no extension, so fall back to the name
+    let key = path
+        .extension()
+        .or_else(|| path.file_name())?
+        .to_str()?
+        .to_lowercase();
+
+    LANGUAGES
+        .iter()
+        .find(|(ext, _)| *ext == key)
+        .map(|(_, language)| *language)
+}
+
+/// Section names for `--group-by-ext` where capitalizing the identifier in
+/// `LANGUAGES` isn't enough.
+const GROUP_NAMES: &[(&str, &str)] = &[
+    ("javascript", "JavaScript"),
+    ("typescript", "TypeScript"),
+    ("jsx", "JSX"),
+    ("tsx", "TSX"),
+    ("cpp", "C++"),
+    ("csharp", "C#"),
+    ("php", "PHP"),
+    ("html", "HTML"),
+    ("css", "CSS"),
+    ("scss", "SCSS"),
+    ("xml", "XML"),
+    ("sql", "SQL"),
+    ("json", "JSON"),
+    ("yaml", "YAML"),
+    ("toml", "TOML"),
+    ("ocaml", "OCaml"),
+    ("powershell", "PowerShell"),
+];
+
+/// The `--group-by-ext` group a path belongs to, such as `Rust` for `.rs`
+/// files. Unknown extensions are grouped by the extension itself (`.txt`);
+/// files without one return `None`.
+pub fn group_for_path(path: &Path) -> Option<String> {
+    if let Some(language) = language_for_path(path) {
+        if let Some((_, name)) = GROUP_NAMES.iter().find(|(id, _)| *id == language) {
+            return Some(name.to_string());
+        }
+        let mut chars = language.chars();
+        return chars.next().map(|first| first.to_uppercase().chain(chars).collect());
+    }
+
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    Some(format!(".{}", extension))
+}
+
+/// Line comment delimiters (opening, closing) for languages whose comments
+/// aren't `#`, keyed by the identifiers in `LANGUAGES`.
+const COMMENTS: &[(&str, (&str, &str))] = &[
+    ("rust", ("//", "")),
+    ("javascript", ("//", "")),
+    ("jsx", ("//", "")),
+    ("typescript", ("//", "")),
+    ("tsx", ("//", "")),
+    ("go", ("//", "")),
+    ("java", ("//", "")),
+    ("kotlin", ("//", "")),
+    ("swift", ("//", "")),
+    ("c", ("//", "")),
+    ("cpp", ("//", "")),
+    ("csharp", ("//", "")),
+    ("php", ("//", "")),
+    ("scss", ("//", "")),
+    ("scala", ("//", "")),
+    ("dart", ("//", "")),
+    ("css", ("/*", " */")),
+    ("ocaml", ("(*", " *)")),
+    ("sql", ("--", "")),
+    ("lua", ("--", "")),
+    ("haskell", ("--", "")),
+    ("erlang", ("%", "")),
+    ("html", ("<!--", " -->")),
+    ("xml", ("<!--", " -->")),
+    ("markdown", ("<!--", " -->")),
+    ("vue", ("<!--", " -->")),
+    ("svelte", ("<!--", " -->")),
+];
+
+/// Returns the comment delimiters for a path's language, falling back to `#`
+/// (which also covers Python, Ruby, shells, YAML and TOML).
+pub fn comment_syntax_for_path(path: &Path) -> (&'static str, &'static str) {
+    language_for_path(path)
+        .and_then(|language| COMMENTS.iter().find(|(name, _)| *name == language))
+        .map(|(_, syntax)| *syntax)
+        .unwrap_or(("#", ""))
+}
+
+/// What `--strip-comments` may remove for a language: its line comment
+/// marker, and its block comment delimiters for `--strip-block-comments`.
+pub struct StripSyntax {
+    pub line: Option<&'static str>,
+    pub block: Option<(&'static str, &'static str)>,
+}
+
+const C_STYLE: StripSyntax = StripSyntax { line: Some("//"), block: Some(("/*", "*/")) };
+const HASH: StripSyntax = StripSyntax { line: Some("#"), block: None };
+const MARKUP: StripSyntax = StripSyntax { line: None, block: Some(("<!--", "-->")) };
+
+const STRIP_SYNTAX: &[(&str, StripSyntax)] = &[
+    ("rust", C_STYLE),
+    ("javascript", C_STYLE),
+    ("jsx", C_STYLE),
+    ("typescript", C_STYLE),
+    ("tsx", C_STYLE),
+    ("go", C_STYLE),
+    ("java", C_STYLE),
+    ("kotlin", C_STYLE),
+    ("swift", C_STYLE),
+    ("c", C_STYLE),
+    ("cpp", C_STYLE),
+    ("csharp", C_STYLE),
+    ("php", C_STYLE),
+    ("scss", C_STYLE),
+    ("scala", C_STYLE),
+    ("dart", C_STYLE),
+    ("css", StripSyntax { line: None, block: Some(("/*", "*/")) }),
+    ("python", HASH),
+    ("ruby", HASH),
+    ("bash", HASH),
+    ("zsh", HASH),
+    ("fish", HASH),
+    ("elixir", HASH),
+    ("yaml", HASH),
+    ("toml", HASH),
+    ("dockerfile", HASH),
+    ("makefile", HASH),
+    ("powershell", StripSyntax { line: Some("#"), block: Some(("<#", "#>")) }),
+    ("sql", StripSyntax { line: Some("--"), block: Some(("/*", "*/")) }),
+    ("lua", StripSyntax { line: Some("--"), block: Some(("--[[", "]]")) }),
+    ("haskell", StripSyntax { line: Some("--"), block: Some(("{-", "-}")) }),
+    ("erlang", StripSyntax { line: Some("%"), block: None }),
+    ("ocaml", StripSyntax { line: None, block: Some(("(*", "*)")) }),
+    ("html", MARKUP),
+    ("xml", MARKUP),
+    ("markdown", MARKUP),
+    ("vue", MARKUP),
+    ("svelte", MARKUP),
+];
+
+/// Returns the comment syntax `--strip-comments` uses for a path, or `None`
+/// for languages it doesn't know, which are left untouched.
+pub fn strip_syntax_for_path(path: &Path) -> Option<&'static StripSyntax> {
+    let language = language_for_path(path)?;
+    STRIP_SYNTAX
+        .iter()
+        .find(|(name, _)| *name == language)
+        .map(|(_, syntax)| syntax)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comment_headers_use_the_language_comment_syntax() {
+        assert_eq!(comment_syntax_for_path(Path::new("src/main.rs")), ("//", ""));
+        assert_eq!(comment_syntax_for_path(Path::new("index.HTML")), ("<!--", " -->"));
+        assert_eq!(comment_syntax_for_path(Path::new("style.css")), ("/*", " */"));
+        assert_eq!(comment_syntax_for_path(Path::new("query.sql")), ("--", ""));
+        assert_eq!(comment_syntax_for_path(Path::new("script.py")), ("#", ""));
+        assert_eq!(comment_syntax_for_path(Path::new("notes.unknown")), ("#", ""));
+    }
+}
@@ -0,0 +1,34 @@
+//! Aggregates the contents of files matched by glob patterns, as used by the
+//! `agg-files` command. To embed it, parse arguments with
+//! [`CliArgs::parse_from`], build a processor with
+//! [`FileProcessor::without_output`] and call [`FileProcessor::aggregate`]
+//! for the selected files and their contents instead of printing them.
+
+pub mod config;
+pub mod archive;
+pub mod binary;
+pub mod cli;
+pub mod clipboard;
+pub mod content;
+pub mod file_processor;
+pub mod git;
+pub mod gitattributes;
+pub mod gitignore_helper;
+pub mod header;
+pub mod pattern_matcher;
+pub mod progress;
+pub mod since;
+pub mod size;
+pub mod github_handler;
+pub mod language;
+pub mod output;
+pub mod temp_manager;
+pub mod tokenizer;
+pub mod tree;
+pub mod version;
+pub mod watch;
+
+pub use cli::CliArgs;
+pub use config::Config;
+pub use file_processor::{AggregatedFile, FileProcessor};
+pub use output::Output;
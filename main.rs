@@ -1,59 +1,190 @@
-mod config;
-mod cli;
-mod file_processor;
-mod gitignore_helper;
-mod pattern_matcher;
-mod github_handler;
-mod temp_manager;
-mod version;
-
-use cli::CliArgs;
-use file_processor::FileProcessor;
-use github_handler::GitHubHandler;
-use temp_manager::TempManager;
-use std::path::PathBuf;
-use version::Version;
+use agg_files::github_handler::{GitHubHandler, RepoInfo};
+use agg_files::temp_manager::{CacheEntry, TempManager};
+use agg_files::version::Version;
+use agg_files::{git, watch, CliArgs, Config, FileProcessor, Output};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
 
 #[tokio::main]
 async fn main() {
-    let args = CliArgs::parse();
+    let mut args = match CliArgs::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
     
     if args.show_version {
         Version::print();
         return;
     }
 
+    let config = match args.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    args.apply_config(&config);
+
     if !args.is_valid() {
         args.print_usage();
         return;
     }
 
-    let working_dir = if let Some(url) = &args.github_url {
-        match process_github_url(url).await {
-            Ok(dir) => dir,
+    if args.from_stdin {
+        match CliArgs::read_path_list(std::io::stdin().lock()) {
+            Ok(paths) => args.stdin_paths = paths,
+            Err(e) => {
+                eprintln!("Error reading paths from stdin: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut sources = Vec::new();
+    for url in &args.github_url {
+        match process_github_url(&args, url).await {
+            Ok(dir) => sources.push(dir),
+            Err(e) => {
+                eprintln!("Error processing GitHub URL {}: {}", url, e);
+                process::exit(1);
+            }
+        }
+    }
+    if sources.len() > 1 {
+        match FileProcessor::aggregate_sources(args, &sources, config) {
+            Ok(0) => return,
+            Ok(_) => process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    let working_dir = sources.pop().unwrap_or_else(|| PathBuf::from("."));
+
+    if let Some(git_ref) = args.changed_since.clone() {
+        match git::changed_files(&working_dir, &git_ref) {
+            Ok(files) => args.restrict_to_changed(&working_dir, files),
+            Err(e) => {
+                eprintln!("Error listing files changed since {}: {}", git_ref, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if args.tracked_only {
+        match git::tracked_files(&working_dir) {
+            Ok(files) => args.restrict_to_tracked(&working_dir, files),
             Err(e) => {
-                eprintln!("Error processing GitHub URL: {}", e);
-                return;
+                eprintln!("Error listing tracked files: {}", e);
+                process::exit(1);
             }
         }
-    } else {
-        PathBuf::from(".")
+    }
+
+    if args.watch {
+        // Only clear when the output is going to a terminal the user is watching
+        let clear_screen = args.output.is_none() && !args.clipboard && std::io::stdout().is_terminal();
+        let result = watch::watch(&working_dir, clear_screen, || {
+            aggregate(args.clone(), &working_dir, config.clone())
+        });
+        if let Err(e) = result {
+            eprintln!("Error watching for changes: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let processor = aggregate(args, &working_dir, config);
+
+    // Files that failed to read are reported as they happen; fail the run so CI notices
+    if processor.failed_files() > 0 {
+        process::exit(1);
+    }
+}
+
+/// Runs one aggregation pass. Errors setting up the output are fatal, as are
+/// write errors outside of watch mode.
+fn aggregate(args: CliArgs, working_dir: &Path, config: Config) -> FileProcessor {
+    let watching = args.watch;
+    let output = match Output::create(&args) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Error creating output file: {}", e);
+            process::exit(1);
+        }
     };
 
-    let processor = FileProcessor::new(args, working_dir);
-    processor.process();
+    let processor = match FileProcessor::new(args, working_dir.to_path_buf(), output, config) {
+        Ok(processor) => processor,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = processor.process() {
+        // A downstream reader closing the pipe early (e.g. `| head`) is not an error
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            eprintln!("Error writing output: {}", e);
+            if !watching {
+                process::exit(1);
+            }
+        }
+    }
+    processor
 }
 
-async fn process_github_url(url: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+async fn process_github_url(args: &CliArgs, url: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
     let github_handler = GitHubHandler::new();
     let repo_info = github_handler.parse_url(url)?;
-    
+
     let temp_manager = TempManager::new();
-    let repo_path = temp_manager.get_repo_path(&repo_info);
+    let cached = temp_manager.cached_entry(&repo_info);
+    let commit = match cached {
+        Some(entry) if !args.refresh && entry.is_fresh(args.cache_ttl, SystemTime::now()) => entry.commit,
+        cached => match update_cache(&github_handler, &temp_manager, &repo_info, args.refresh, cached.as_ref()).await {
+            Ok(commit) => commit,
+            // Working offline against a copy that may be stale beats not working
+            Err(e) => match cached {
+                Some(entry) => {
+                    if !args.quiet {
+                        eprintln!("Warning: {}; using the cached copy at commit {}", e, &entry.commit[..entry.commit.len().min(12)]);
+                    }
+                    entry.commit
+                }
+                None => return Err(e),
+            },
+        },
+    };
 
-    if !temp_manager.repo_exists(&repo_info) {
-        github_handler.download_repository(&repo_info).await?;
+    let repo_path = temp_manager.get_repo_path(&repo_info, &commit);
+    if !repo_path.exists() {
+        let path = repo_info.path.as_deref().unwrap_or_default();
+        return Err(format!("Path '{}' not found in repository", path).into());
     }
-
     Ok(repo_path)
 }
+
+/// Checks which commit the branch is at and downloads it unless it's already
+/// the cached one (or `--refresh` was given). Returns the commit.
+async fn update_cache(
+    github_handler: &GitHubHandler,
+    temp_manager: &TempManager,
+    repo_info: &RepoInfo,
+    refresh: bool,
+    cached: Option<&CacheEntry>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let commit = github_handler.resolve_commit(repo_info).await?;
+    let up_to_date = !refresh && cached.is_some_and(|entry| entry.commit == commit);
+    if !up_to_date {
+        github_handler.download_repository(repo_info, &commit).await?;
+    }
+    temp_manager.record_entry(repo_info, &commit)?;
+    Ok(commit)
+}
@@ -0,0 +1,280 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cli::CliArgs;
+use crate::clipboard;
+
+/// Where output bytes go: straight through, or gzip-compressed as they're
+/// written with `--gzip`.
+enum Writer {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Plain(writer) => writer.write(buf),
+            Writer::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Plain(writer) => writer.flush(),
+            // Flushing alone would leave the gzip stream without its trailer
+            Writer::Gzip(encoder) => encoder.try_finish().and_then(|()| encoder.get_mut().flush()),
+        }
+    }
+}
+
+impl Writer {
+    fn open(path: &Path, gzip: bool) -> io::Result<Self> {
+        let file: Box<dyn Write> = Box::new(BufWriter::new(File::create(path)?));
+        Ok(Self::wrap(file, gzip))
+    }
+
+    fn wrap(writer: Box<dyn Write>, gzip: bool) -> Self {
+        if gzip {
+            Writer::Gzip(GzEncoder::new(writer, Compression::default()))
+        } else {
+            Writer::Plain(writer)
+        }
+    }
+}
+
+/// State for `--split`: the text of the entry being written is held back
+/// until the next entry starts, so it can go to a fresh chunk as a whole.
+struct Split {
+    base: PathBuf,
+    limit: u64,
+    gzip: bool,
+    chunk: usize,
+    chunk_len: u64,
+    pending: String,
+}
+
+/// The path of chunk `index` (from 1) for `--split`, e.g. `out.txt.003`.
+pub fn chunk_path(base: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(base.as_os_str());
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+pub struct Output {
+    writer: Writer,
+    buffer: Option<String>,
+    split: Option<Split>,
+    to_file: bool,
+    clipboard: bool,
+    quiet: bool,
+    error: Option<io::Error>,
+}
+
+impl Output {
+    /// Writes to `--output` or stdout. With `--buffered` (or `--clipboard`,
+    /// which needs the whole text), nothing reaches the writer until `finish`,
+    /// so a reader that exits early never sees a partially written file.
+    /// With `--gzip` the stream is compressed on the fly as it's written.
+    /// With `--split` the output goes to numbered chunks next to `--output`
+    /// instead, each gzipped on its own. `--append-output` adds to the end of
+    /// an existing `--output` file.
+    pub fn create(args: &CliArgs) -> io::Result<Self> {
+        if let (Some(limit), Some(base)) = (args.split, &args.output) {
+            return Ok(Self {
+                writer: Writer::open(&chunk_path(base, 1), args.gzip)?,
+                buffer: None,
+                split: Some(Split {
+                    base: base.clone(),
+                    limit,
+                    gzip: args.gzip,
+                    chunk: 1,
+                    chunk_len: 0,
+                    pending: String::new(),
+                }),
+                to_file: true,
+                clipboard: false,
+                quiet: args.quiet,
+                error: None,
+            });
+        }
+
+        let writer: Box<dyn Write> = match &args.output {
+            Some(path) if args.append_output => {
+                Box::new(BufWriter::new(File::options().append(true).create(true).open(path)?))
+            }
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout()),
+        };
+        // The clipboard always gets plain text; when it replaces stdout there's
+        // nothing to compress
+        let writer = Writer::wrap(writer, args.gzip && !(args.clipboard && args.output.is_none()));
+
+        Ok(Self {
+            writer,
+            buffer: (args.buffered || args.clipboard || args.toc).then(String::new),
+            split: None,
+            to_file: args.output.is_some(),
+            clipboard: args.clipboard,
+            quiet: args.quiet,
+            error: None,
+        })
+    }
+
+    /// An output that throws everything away, for a processor that's only
+    /// used to collect files.
+    pub fn discard() -> Self {
+        Self {
+            writer: Writer::Plain(Box::new(io::sink())),
+            buffer: None,
+            split: None,
+            to_file: false,
+            clipboard: false,
+            quiet: true,
+            error: None,
+        }
+    }
+
+    pub fn write_line(&mut self, line: &str) {
+        self.write_str(line);
+        self.write_str("\n");
+    }
+
+    pub fn write_str(&mut self, text: &str) {
+        if let Some(buffer) = &mut self.buffer {
+            buffer.push_str(text);
+            return;
+        }
+        if let Some(split) = &mut self.split {
+            split.pending.push_str(text);
+            return;
+        }
+        self.write_through(text);
+    }
+
+    /// Bytes written so far with `--buffered` (or `--toc`), which is where the
+    /// next write will start.
+    pub fn buffered_len(&self) -> Option<usize> {
+        self.buffer.as_ref().map(String::len)
+    }
+
+    /// Puts `text` in front of everything buffered so far.
+    pub fn prepend(&mut self, text: &str) {
+        if let Some(buffer) = &mut self.buffer {
+            buffer.insert_str(0, text);
+        }
+    }
+
+    /// Marks the start of a file's entry. With `--split`, everything written
+    /// since the previous call is placed as one unit, starting a new chunk
+    /// first if it wouldn't fit in the current one.
+    pub fn start_entry(&mut self) {
+        let Some(split) = &mut self.split else {
+            return;
+        };
+        let pending = std::mem::take(&mut split.pending);
+        if pending.is_empty() {
+            return;
+        }
+
+        let len = pending.len() as u64;
+        // An entry larger than the limit still gets a chunk to itself
+        if split.chunk_len > 0 && split.chunk_len + len > split.limit {
+            split.chunk += 1;
+            split.chunk_len = 0;
+            if self.error.is_none() {
+                let path = chunk_path(&split.base, split.chunk);
+                let next = self.writer.flush().and_then(|()| Writer::open(&path, split.gzip));
+                match next {
+                    Ok(writer) => self.writer = writer,
+                    Err(e) => self.error = Some(e),
+                }
+            }
+        }
+        split.chunk_len += len;
+        self.write_through(&pending);
+    }
+
+    fn write_through(&mut self, text: &str) {
+        // Stop writing after the first failure (e.g. a closed pipe) and
+        // report it once from `finish`
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.writer.write_all(text.as_bytes()) {
+            self.error = Some(e);
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.start_entry();
+        if let Some(buffer) = self.buffer.take() {
+            if self.clipboard {
+                match clipboard::copy(&buffer) {
+                    Ok(()) => {
+                        if !self.quiet {
+                            eprintln!("Copied {} bytes to the clipboard", buffer.len());
+                        }
+                        // The clipboard replaces stdout, but an --output file is still written
+                        if !self.to_file {
+                            return Ok(());
+                        }
+                    }
+                    // Headless sessions have no clipboard; don't lose the output
+                    Err(e) => eprintln!("Could not copy to the clipboard ({}), writing output instead", e),
+                }
+            }
+            self.write_str(&buffer);
+        }
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn split_output(base: &Path, limit: &str) -> Output {
+        let argv = ["agg-files", "-o", base.to_str().unwrap(), "--split", limit].map(String::from);
+        Output::create(&CliArgs::parse_from(argv).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn chunks_are_numbered_after_the_output() {
+        assert_eq!(chunk_path(Path::new("out/context.txt"), 1), PathBuf::from("out/context.txt.001"));
+        assert_eq!(chunk_path(Path::new("context.txt"), 12), PathBuf::from("context.txt.012"));
+    }
+
+    #[test]
+    fn entries_never_straddle_a_chunk() {
+        let dir = std::env::temp_dir().join(format!("agg-files-split-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("out.txt");
+
+        let mut output = split_output(&base, "25");
+        for entry in ["first....\n", "second...\n", "third....\n", "a long entry over the limit\n", "last.....\n"] {
+            output.start_entry();
+            output.write_str(entry);
+        }
+        output.finish().unwrap();
+
+        let chunk = |index| fs::read_to_string(chunk_path(&base, index)).unwrap();
+        // Two 10-byte entries fit in 25 bytes, a third would not
+        assert_eq!(chunk(1), "first....\nsecond...\n");
+        assert_eq!(chunk(2), "third....\n");
+        // An entry over the limit gets a chunk to itself
+        assert_eq!(chunk(3), "a long entry over the limit\n");
+        assert_eq!(chunk(4), "last.....\n");
+        assert!(!chunk_path(&base, 5).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,21 +1,267 @@
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
+use std::path::Path;
 
-pub struct PatternMatcher;
+pub struct PatternMatcher {
+    ignore_case: bool,
+}
 
 impl PatternMatcher {
-    pub fn new() -> Self {
-        Self
-    }
-
-    pub fn glob_to_regex(&self, pattern: &str) -> Regex {
-        let regex_str = pattern
-            .replace(".", "\\.")
-            .replace("*", ".*")
-            .replace("{", "(")
-            .replace("}", ")")
-            .replace(",", "|")
-            .replace(" ", "");  // Remove spaces
-        
-        Regex::new(&format!(".*{}$", regex_str)).unwrap()
+    pub fn new(ignore_case: bool) -> Self {
+        Self { ignore_case }
+    }
+
+    /// Translates a glob into a regex that must match the whole path or a
+    /// trailing run of whole path segments, so `main.rs` matches
+    /// `./src/main.rs` but not `./src/xmain.rs`. As in `.gitignore`, a
+    /// pattern with a `/` before its end is anchored instead: `src/*.rs`
+    /// matches `src/main.rs` but not `lib/src/main.rs`.
+    pub fn glob_to_regex(&self, pattern: &str) -> Result<Regex, regex::Error> {
+        self.build(&format!("^{}{}$", prefix(pattern), translate(pattern)))
+    }
+
+    /// Translates a glob into a regex for a file name alone, used with
+    /// `--match-basename` for patterns without a `/`.
+    pub fn basename_regex(&self, pattern: &str) -> Result<Regex, regex::Error> {
+        self.build(&format!("^{}$", translate(pattern)))
+    }
+
+    /// Like `glob_to_regex`, but also matches everything beneath a matching
+    /// directory. Used for ignore and exclude rules, which only ever see
+    /// relative paths, so a leading `/` just anchors the pattern.
+    pub fn ignore_regex(&self, pattern: &str) -> Result<Regex, regex::Error> {
+        let relative = pattern.strip_prefix('/').unwrap_or(pattern);
+        self.build(&format!("^{}{}(?:/.*)?$", prefix(pattern), translate(relative)))
+    }
+
+    fn build(&self, regex_str: &str) -> Result<Regex, regex::Error> {
+        RegexBuilder::new(regex_str)
+            .case_insensitive(self.ignore_case)
+            .build()
+    }
+}
+
+/// The string patterns are matched against: lossy for non-UTF-8 names, and
+/// with `\` separators turned into `/` the way patterns are, so `src\main.rs`
+/// and `src/main.rs` mean the same on every platform.
+pub fn match_subject(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// What may come before the translated pattern: any leading directories for
+/// a bare name like `*.rs` or `src/`, nothing for a pattern with a `/` before
+/// its end. A leading `**/` still floats, since it matches directories itself.
+fn prefix(pattern: &str) -> &'static str {
+    let pattern = pattern.replace('\\', "/");
+    if pattern.trim_start_matches("./").trim_end_matches('/').contains('/') {
+        ""
+    } else {
+        "(?:.*/)?"
+    }
+}
+
+/// Converts glob syntax to regex syntax. Everything except `*`, `**`, `?` and
+/// `{a,b}` groups is matched literally, except that `\` is a path separator
+/// like `/`.
+fn translate(pattern: &str) -> String {
+    let pattern = pattern.replace('\\', "/");
+    let pattern = pattern.trim_start_matches("./");
+    let chars: Vec<char> = pattern.chars().collect();
+    let balanced = balanced_braces(&chars);
+
+    let mut regex_str = String::new();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            // Brace groups become alternations: `*.{rs,toml}` -> `.*\.(rs|toml)`
+            '{' if balanced[i] => {
+                depth += 1;
+                regex_str.push('(');
+            }
+            '}' if balanced[i] => {
+                depth -= 1;
+                regex_str.push(')');
+            }
+            ',' if depth > 0 => regex_str.push('|'),
+            ' ' if depth > 0 => {} // Allow `{rs, toml}`
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                i += 1;
+                if chars.get(i + 1) == Some(&'/') {
+                    // `**/` matches zero or more whole directories
+                    i += 1;
+                    regex_str.push_str("(?:.*/)?");
+                } else {
+                    regex_str.push_str(".*");
+                }
+            }
+            // A single `*` stays within one path segment
+            '*' => regex_str.push_str("[^/]*"),
+            '?' => regex_str.push_str("[^/]"),
+            _ => regex_str.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+
+    regex_str
+}
+
+/// Marks which braces in `chars` belong to a matched `{...}` pair. Unmatched
+/// braces are treated as literal characters.
+fn balanced_braces(chars: &[char]) -> Vec<bool> {
+    let mut balanced = vec![false; chars.len()];
+    let mut open = Vec::new();
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            '{' => open.push(i),
+            '}' => {
+                if let Some(start) = open.pop() {
+                    balanced[start] = true;
+                    balanced[i] = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    balanced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str) -> Regex {
+        PatternMatcher::new(false).glob_to_regex(pattern).unwrap()
+    }
+
+    fn ignore(pattern: &str) -> Regex {
+        PatternMatcher::new(false).ignore_regex(pattern).unwrap()
+    }
+
+    #[test]
+    fn single_star_stays_within_a_segment() {
+        assert_eq!(translate("*.rs"), r"[^/]*\.rs");
+        assert_eq!(translate("a?c"), "a[^/]c");
+        assert!(glob("*.rs").is_match("main.rs"));
+        assert!(glob("*.rs").is_match("src/main.rs"));
+        assert!(!glob("*.rs").is_match("main.rsx"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert_eq!(translate("src/**/*.rs"), r"src/(?:.*/)?[^/]*\.rs");
+        assert_eq!(translate("src/**"), "src/.*");
+        assert!(glob("src/**/*.rs").is_match("src/main.rs"));
+        assert!(glob("src/**/*.rs").is_match("src/a/b/main.rs"));
+        assert!(glob("**/*.rs").is_match("a/b/main.rs"));
+        assert!(!glob("src/*.rs").is_match("src/a/b.rs"));
+        assert!(glob("src/**/*.rs").is_match("src/a/b.rs"));
+        assert!(!glob("src/**/*.rs").is_match("src/a/b.rsx"));
+    }
+
+    #[test]
+    fn braces_become_alternations() {
+        assert_eq!(translate("*.{rs,toml}"), r"[^/]*\.(rs|toml)");
+        assert_eq!(translate("*.{rs, toml}"), r"[^/]*\.(rs|toml)");
+        assert!(glob("*.{rs,toml}").is_match("Cargo.toml"));
+        assert!(!glob("*.{rs,toml}").is_match("README.md"));
+        // Unmatched braces are literal
+        assert_eq!(translate("a{b"), r"a\{b");
+        assert!(glob("a}b,c").is_match("a}b,c"));
+    }
+
+    #[test]
+    fn nested_and_empty_braces() {
+        for name in ["a", "b", "c"] {
+            assert!(glob("{a,b,c}").is_match(name), "{}", name);
+        }
+        assert!(!glob("{a,b,c}").is_match("d"));
+        assert!(glob("file.{rs,toml}").is_match("file.rs"));
+        assert!(glob("file.{rs,toml}").is_match("src/file.toml"));
+        assert!(!glob("file.{rs,toml}").is_match("file.md"));
+
+        let nested = glob("*.{rs,{js,ts}x}");
+        assert!(nested.is_match("a.rs"));
+        assert!(nested.is_match("a.jsx"));
+        assert!(nested.is_match("a.tsx"));
+        assert!(!nested.is_match("a.ts"));
+
+        assert!(glob("main{}.rs").is_match("main.rs"));
+        assert!(glob("main{,.test}.rs").is_match("main.rs"));
+        assert!(glob("main{,.test}.rs").is_match("main.test.rs"));
+        assert!(glob("{a,b").is_match("{a,b"));
+    }
+
+    #[test]
+    fn everything_else_is_literal() {
+        assert!(glob("file(1).txt").is_match("file(1).txt"));
+        assert!(!glob("file(1).txt").is_match("file1.txt"));
+        assert!(glob("data[0].json").is_match("data[0].json"));
+        assert!(!glob("data[0].json").is_match("data0.json"));
+        assert!(glob("a+(b)[c].rs").is_match("a+(b)[c].rs"));
+        assert!(glob("^$|.rs").is_match("^$|.rs"));
+        assert!(!glob("a.rs").is_match("abrs"));
+        assert!(!glob("main.rs").is_match("src/xmain.rs"));
+    }
+
+    #[test]
+    fn backslashes_and_leading_dot_slash_are_separators() {
+        assert_eq!(translate(r"src\main.rs"), translate("src/main.rs"));
+        assert_eq!(translate("./src/main.rs"), translate("src/main.rs"));
+        assert!(glob(r"src\*.rs").is_match("src/main.rs"));
+    }
+
+    #[test]
+    fn bare_names_match_at_any_depth() {
+        assert!(glob("main.rs").is_match("src/main.rs"));
+        assert!(glob("src/").is_match("lib/src/"));
+        assert!(ignore("target").is_match("target"));
+        assert!(ignore("target").is_match("crates/a/target/debug/x"));
+        assert!(!ignore("target").is_match("my-target.txt"));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_are_anchored() {
+        assert!(glob("src/*.rs").is_match("src/q.rs"));
+        assert!(!glob("src/*.rs").is_match("lib/src/q.rs"));
+        assert!(glob("src/**").is_match("src/a/q.rs"));
+        assert!(!glob("src/**").is_match("lib/src/q.rs"));
+        assert!(!glob("./src/*.rs").is_match("lib/src/q.rs"));
+        assert!(!glob(r"src\*.rs").is_match("lib/src/q.rs"));
+        // A leading `**/` still reaches any depth
+        assert!(glob("**/src/*.rs").is_match("lib/src/q.rs"));
+        // Absolute patterns match absolute paths from the root
+        assert!(glob("/home/me/src/*.rs").is_match("/home/me/src/q.rs"));
+    }
+
+    #[test]
+    fn ignore_patterns_with_a_slash_are_anchored() {
+        assert!(ignore("docs/gen").is_match("docs/gen/a.md"));
+        assert!(!ignore("docs/gen").is_match("vendor/docs/gen/a.md"));
+        assert!(ignore("/target").is_match("target/debug/x"));
+        assert!(!ignore("/target").is_match("crates/a/target/debug/x"));
+        assert!(ignore("**/gen").is_match("vendor/docs/gen/a.md"));
+    }
+
+    #[test]
+    fn basename_regex_matches_the_name_alone() {
+        let regex = PatternMatcher::new(false).basename_regex("test**").unwrap();
+        assert!(regex.is_match("test_util.rs"));
+        assert!(!regex.is_match("a_test.rs"));
+    }
+
+    #[test]
+    fn ignore_case() {
+        let matcher = PatternMatcher::new(true);
+        assert!(matcher.glob_to_regex("*.MD").unwrap().is_match("readme.md"));
+        assert!(!glob("*.MD").is_match("readme.md"));
+        assert!(matcher.glob_to_regex("SRC/*.rs").unwrap().is_match("src/Main.RS"));
+        assert!(matcher.ignore_regex("Target").unwrap().is_match("target/debug/x"));
+        assert!(!ignore("Target").is_match("target/debug/x"));
+    }
+
+    #[test]
+    fn match_subject_uses_forward_slashes() {
+        assert_eq!(match_subject(Path::new(r"src\main.rs")), "src/main.rs");
     }
 }
@@ -0,0 +1,63 @@
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::IsTerminal;
+use std::time::Duration;
+
+/// The `--progress` bar on stderr: a file count while walking, then a bar
+/// while reading. Does nothing when stderr isn't a terminal.
+pub struct Progress {
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(enabled: bool) -> Self {
+        let bar = (enabled && std::io::stderr().is_terminal()).then(|| {
+            let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+            bar.set_style(ProgressStyle::with_template("{spinner} Scanning: {pos} files").expect("valid template"));
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar
+        });
+        Self { bar }
+    }
+
+    /// Counts a file found by the walk.
+    pub fn found(&self) {
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+
+    /// Switches from counting to a bar over the `total` files to read.
+    pub fn start_reading(&self, total: usize) {
+        if let Some(bar) = &self.bar {
+            bar.set_style(
+                ProgressStyle::with_template("Reading [{bar:30}] {pos}/{len} files")
+                    .expect("valid template")
+                    .progress_chars("=> "),
+            );
+            bar.set_position(0);
+            bar.set_length(total as u64);
+        }
+    }
+
+    /// Counts a file read. Safe to call from the reading threads.
+    pub fn read(&self) {
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+
+    /// Removes the bar, so it never mixes with the output.
+    pub fn clear(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+
+    /// Prints a message to stderr without leaving bits of the bar around it.
+    pub fn println(&self, message: &str) {
+        match &self.bar {
+            Some(bar) if !bar.is_finished() => bar.suspend(|| eprintln!("{}", message)),
+            _ => eprintln!("{}", message),
+        }
+    }
+}
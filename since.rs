@@ -0,0 +1,97 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use std::time::{Duration, SystemTime};
+
+/// Parses a `--modified-since` value into a point in time.
+///
+/// Accepts a relative duration counted back from `now` (`30s`, `15m`, `3h`,
+/// `2d`, `1w`), an RFC 3339 timestamp, or a local `YYYY-MM-DD` date (midnight)
+/// or `YYYY-MM-DDTHH:MM:SS` date-time.
+pub fn parse_since(value: &str, now: SystemTime) -> Option<SystemTime> {
+    let value = value.trim();
+    if let Some(duration) = parse_duration(value) {
+        return now.checked_sub(duration);
+    }
+
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Some(datetime.into());
+    }
+
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(SystemTime::from)
+}
+
+/// Parses a duration such as `30s`, `15m`, `3h`, `2d` or `1w`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let unit = value.chars().last()?;
+    let amount: u64 = value[..value.len() - unit.len_utf8()].parse().ok()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(seconds)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_take_each_unit() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("15m"), Some(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("3h"), Some(Duration::from_secs(3 * 60 * 60)));
+        assert_eq!(parse_duration("2d"), Some(Duration::from_secs(2 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("1w"), Some(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn durations_without_an_amount_or_a_known_unit_are_rejected() {
+        for value in ["", "2x", "d", "2", "-2d", "2.5h", "2 d", "2é"] {
+            assert_eq!(parse_duration(value), None, "{:?}", value);
+        }
+        assert_eq!(parse_duration(&format!("{}w", u64::MAX)), None);
+    }
+
+    #[test]
+    fn relative_values_count_back_from_now() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(parse_since("2d", now), Some(now - Duration::from_secs(2 * 24 * 60 * 60)));
+        assert_eq!(parse_since(" 15m\n", now), Some(now - Duration::from_secs(15 * 60)));
+        // Further back than a SystemTime can go
+        assert_eq!(parse_since(&format!("{}s", u64::MAX), now), None);
+    }
+
+    #[test]
+    fn timestamps_and_local_dates_are_absolute() {
+        let now = SystemTime::now();
+        let expected = SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_164_645);
+        assert_eq!(parse_since("2024-01-02T03:04:05Z", now), Some(expected));
+        assert_eq!(parse_since("2024-01-02T05:04:05+02:00", now), Some(expected));
+
+        let local = |h, m, s| Local.with_ymd_and_hms(2024, 1, 2, h, m, s).earliest().map(SystemTime::from);
+        assert_eq!(parse_since("2024-01-02", now), local(0, 0, 0));
+        assert_eq!(parse_since("2024-01-02T03:04:05", now), local(3, 4, 5));
+    }
+
+    #[test]
+    fn anything_else_is_rejected() {
+        let now = SystemTime::now();
+        for value in ["", "yesterday", "2024-13-01", "2024-01-02 03:04:05", "2x"] {
+            assert_eq!(parse_since(value, now), None, "{:?}", value);
+        }
+    }
+}
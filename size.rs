@@ -0,0 +1,68 @@
+/// Parses a human-readable byte size such as `512`, `10k`, `2M` or `1.5GB`.
+///
+/// Suffixes are case-insensitive binary multiples (`k` = 1024), and an
+/// optional trailing `b`/`ib` is accepted, so `10k`, `10kb` and `10KiB` are
+/// equivalent. Returns `None` for anything that isn't a non-negative size.
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let multiplier: u64 = match unit.trim_start() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return None,
+    };
+
+    if let Ok(whole) = number.parse::<u64>() {
+        return whole.checked_mul(multiplier);
+    }
+
+    let fractional = number.parse::<f64>().ok()?;
+    if !fractional.is_finite() || fractional < 0.0 {
+        return None;
+    }
+    Some((fractional * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_numbers_are_bytes() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("512b"), Some(512));
+        assert_eq!(parse_size(" 512 "), Some(512));
+    }
+
+    #[test]
+    fn suffixes_are_binary_multiples_in_any_case() {
+        assert_eq!(parse_size("10k"), Some(10 * 1024));
+        assert_eq!(parse_size("10KB"), Some(10 * 1024));
+        assert_eq!(parse_size("10KiB"), Some(10 * 1024));
+        assert_eq!(parse_size("2M"), Some(2 << 20));
+        assert_eq!(parse_size("3 mb"), Some(3 << 20));
+        assert_eq!(parse_size("1g"), Some(1 << 30));
+    }
+
+    #[test]
+    fn fractions_are_rounded_down() {
+        assert_eq!(parse_size("1.5GB"), Some(3 << 29));
+        assert_eq!(parse_size("0.5k"), Some(512));
+        assert_eq!(parse_size("1.0001"), Some(1));
+    }
+
+    #[test]
+    fn anything_else_is_rejected() {
+        for value in ["", "k", "-1", "-1k", "10x", "10tb", "1.2.3", "ten"] {
+            assert_eq!(parse_size(value), None, "{}", value);
+        }
+        assert_eq!(parse_size(&format!("{}g", u64::MAX)), None);
+    }
+}
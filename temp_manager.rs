@@ -1,19 +1,47 @@
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use crate::github_handler::RepoInfo;
 
+/// Name of the file recording which commit of a branch is cached
+const ENTRY_FILE: &str = "cache.json";
+
+/// The cached copy of a branch: the commit it was downloaded at and when
+/// that commit was last confirmed against GitHub.
+#[derive(Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub commit: String,
+    /// Seconds since the Unix epoch
+    pub fetched_at: u64,
+}
+
+impl CacheEntry {
+    /// Whether the entry was confirmed less than `ttl` ago.
+    pub fn is_fresh(&self, ttl: Duration, now: SystemTime) -> bool {
+        let now = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        now.saturating_sub(self.fetched_at) < ttl.as_secs()
+    }
+}
+
 pub struct TempManager {
     base_dir: PathBuf,
 }
 
+impl Default for TempManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TempManager {
     pub fn new() -> Self {
         let project_dirs = ProjectDirs::from("com", "seth4242", "agg-files")
             .expect("Failed to get project directories");
-        
+
         let base_dir = project_dirs.cache_dir().to_path_buf();
-        
+
         // Create base directory if it doesn't exist
         fs::create_dir_all(&base_dir).unwrap_or_else(|_| {
             eprintln!("Warning: Failed to create cache directory");
@@ -22,11 +50,21 @@ impl TempManager {
         Self { base_dir }
     }
 
-    pub fn get_repo_path(&self, repo_info: &RepoInfo) -> PathBuf {
-        let repo_dir = self.base_dir
+    fn branch_dir(&self, repo_info: &RepoInfo) -> PathBuf {
+        self.base_dir
             .join(&repo_info.owner)
             .join(&repo_info.repo)
-            .join(&repo_info.branch);
+            .join(&repo_info.branch)
+    }
+
+    /// Where the whole repository is extracted at `commit`
+    pub fn get_commit_path(&self, repo_info: &RepoInfo, commit: &str) -> PathBuf {
+        self.branch_dir(repo_info).join(commit)
+    }
+
+    /// The directory to aggregate: the requested path within the cached commit
+    pub fn get_repo_path(&self, repo_info: &RepoInfo, commit: &str) -> PathBuf {
+        let repo_dir = self.get_commit_path(repo_info, commit);
 
         if let Some(path) = &repo_info.path {
             repo_dir.join(path)
@@ -35,7 +73,101 @@ impl TempManager {
         }
     }
 
-    pub fn repo_exists(&self, repo_info: &RepoInfo) -> bool {
-        self.get_repo_path(repo_info).exists()
+    /// Scratch directory a download is extracted into before being moved into place
+    pub fn get_staging_path(&self, repo_info: &RepoInfo, commit: &str) -> PathBuf {
+        self.base_dir
+            .join(".staging")
+            .join(format!("{}-{}-{}", repo_info.owner, repo_info.repo, commit))
+    }
+
+    /// The cached copy of the branch, if one was recorded and is still on disk.
+    pub fn cached_entry(&self, repo_info: &RepoInfo) -> Option<CacheEntry> {
+        let contents = fs::read_to_string(self.branch_dir(repo_info).join(ENTRY_FILE)).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&contents).ok()?;
+        self.get_commit_path(repo_info, &entry.commit).is_dir().then_some(entry)
+    }
+
+    /// Records `commit` as the cached copy of the branch, confirmed now, and
+    /// removes copies of older commits.
+    pub fn record_entry(&self, repo_info: &RepoInfo, commit: &str) -> std::io::Result<()> {
+        let branch_dir = self.branch_dir(repo_info);
+        let entry = CacheEntry {
+            commit: commit.to_string(),
+            fetched_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        };
+        fs::create_dir_all(&branch_dir)?;
+        fs::write(branch_dir.join(ENTRY_FILE), serde_json::to_string(&entry)?)?;
+
+        for dir_entry in fs::read_dir(&branch_dir)?.flatten() {
+            let name = dir_entry.file_name();
+            if name != commit && name != ENTRY_FILE {
+                let path = dir_entry.path();
+                let _ = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo_info(path: Option<&str>) -> RepoInfo {
+        RepoInfo {
+            owner: "org".to_string(),
+            repo: "repo".to_string(),
+            branch: "main".to_string(),
+            path: path.map(str::to_string),
+        }
+    }
+
+    fn temp_manager(name: &str) -> TempManager {
+        let base_dir = std::env::temp_dir().join(format!("agg-files-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&base_dir);
+        TempManager { base_dir }
+    }
+
+    #[test]
+    fn entries_are_fresh_until_the_ttl_passes() {
+        let entry = CacheEntry { commit: "abc".to_string(), fetched_at: 1_000 };
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let ttl = Duration::from_secs(60);
+        assert!(entry.is_fresh(ttl, at(1_000)));
+        assert!(entry.is_fresh(ttl, at(1_059)));
+        assert!(!entry.is_fresh(ttl, at(1_060)));
+        assert!(!entry.is_fresh(Duration::ZERO, at(1_000)));
+        // A clock behind the entry still counts as fresh
+        assert!(entry.is_fresh(ttl, at(10)));
+    }
+
+    #[test]
+    fn copies_are_keyed_by_repository_branch_and_commit() {
+        let manager = temp_manager("keys");
+        let commit_path = manager.get_commit_path(&repo_info(None), "abc");
+        assert_eq!(commit_path, manager.base_dir.join("org/repo/main/abc"));
+        assert_eq!(manager.get_repo_path(&repo_info(None), "abc"), commit_path);
+        assert_eq!(manager.get_repo_path(&repo_info(Some("src/lib")), "abc"), commit_path.join("src/lib"));
+    }
+
+    #[test]
+    fn recording_a_commit_replaces_older_copies() {
+        let manager = temp_manager("record");
+        let info = repo_info(None);
+        assert!(manager.cached_entry(&info).is_none());
+
+        fs::create_dir_all(manager.get_commit_path(&info, "old")).unwrap();
+        manager.record_entry(&info, "old").unwrap();
+        assert_eq!(manager.cached_entry(&info).unwrap().commit, "old");
+
+        fs::create_dir_all(manager.get_commit_path(&info, "new")).unwrap();
+        manager.record_entry(&info, "new").unwrap();
+        assert_eq!(manager.cached_entry(&info).unwrap().commit, "new");
+        assert!(!manager.get_commit_path(&info, "old").exists());
+
+        // An entry whose copy is gone isn't used
+        fs::remove_dir_all(manager.get_commit_path(&info, "new")).unwrap();
+        assert!(manager.cached_entry(&info).is_none());
+        fs::remove_dir_all(&manager.base_dir).unwrap();
     }
 }
@@ -0,0 +1,51 @@
+/// Characters per token assumed for long words, roughly matching common BPE vocabularies.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Estimates how many tokens an LLM would see for `text`.
+///
+/// This is an approximation, not any specific model's tokenizer: each run of
+/// alphanumeric characters counts as one token per started group of four
+/// characters, every other non-whitespace character (punctuation, symbols)
+/// counts as one token, and whitespace is free. The result is deterministic
+/// for a given input.
+pub fn estimate_tokens(text: &str) -> usize {
+    let mut tokens = 0;
+    let mut word_len: usize = 0;
+
+    for c in text.chars() {
+        if c.is_alphanumeric() || c == '_' {
+            word_len += 1;
+            continue;
+        }
+
+        tokens += word_len.div_ceil(CHARS_PER_TOKEN);
+        word_len = 0;
+        if !c.is_whitespace() {
+            tokens += 1;
+        }
+    }
+
+    tokens + word_len.div_ceil(CHARS_PER_TOKEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_count_one_token_per_started_four_characters() {
+        assert_eq!(estimate_tokens("word"), 1);
+        assert_eq!(estimate_tokens("words"), 2);
+        assert_eq!(estimate_tokens("snake_case"), 3);
+        assert_eq!(estimate_tokens("héllo"), 2);
+    }
+
+    #[test]
+    fn punctuation_counts_and_whitespace_is_free() {
+        assert_eq!(estimate_tokens("a.b"), 3);
+        assert_eq!(estimate_tokens("a b"), 2);
+        assert_eq!(estimate_tokens(" \t\n word \r\n"), 1);
+        assert_eq!(estimate_tokens("   "), 0);
+        assert_eq!(estimate_tokens(""), 0);
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
+
+/// A directory in the tree; files are entries with no children.
+#[derive(Default)]
+struct Node {
+    children: BTreeMap<String, Node>,
+    /// A directory known to have nothing in it, shown as `name/ (empty)`
+    empty: bool,
+}
+
+/// Renders paths as an ASCII directory tree rooted at `.`, with each
+/// directory's entries sorted by name. A leading `./` is ignored, so the
+/// display paths used in headers can be passed as-is. `empty_dirs` are
+/// directories to show even though no path lies inside them.
+pub fn render_tree<P: AsRef<Path>, D: AsRef<Path>>(paths: &[P], empty_dirs: &[D]) -> String {
+    let mut root = Node::default();
+    for path in paths {
+        insert(&mut root, path.as_ref());
+    }
+    for dir in empty_dirs {
+        insert(&mut root, dir.as_ref()).empty = true;
+    }
+
+    let mut tree = String::from(".\n");
+    render_children(&root, "", &mut tree);
+    tree
+}
+
+fn insert<'a>(root: &'a mut Node, path: &Path) -> &'a mut Node {
+    let mut node = root;
+    for component in path.components() {
+        let name = match component {
+            Component::CurDir => continue,
+            Component::RootDir => "/".to_string(),
+            other => other.as_os_str().to_string_lossy().into_owned(),
+        };
+        node = node.children.entry(name).or_default();
+    }
+    node
+}
+
+fn render_children(node: &Node, prefix: &str, tree: &mut String) {
+    let count = node.children.len();
+    for (i, (name, child)) in node.children.iter().enumerate() {
+        let last = i + 1 == count;
+        tree.push_str(prefix);
+        tree.push_str(if last { "└── " } else { "├── " });
+        tree.push_str(name);
+        if child.empty {
+            tree.push_str("/ (empty)");
+        }
+        tree.push('\n');
+
+        let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        render_children(child, &child_prefix, tree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONE: &[&str] = &[];
+
+    #[test]
+    fn paths_become_a_sorted_tree() {
+        let paths = ["./src/main.rs", "./Cargo.toml", "./src/cli/args.rs", "./README.md", "./src/lib.rs"];
+        let expected = "\
+.
+├── Cargo.toml
+├── README.md
+└── src
+    ├── cli
+    │   └── args.rs
+    ├── lib.rs
+    └── main.rs
+";
+        assert_eq!(render_tree(&paths, NONE), expected);
+    }
+
+    #[test]
+    fn the_dot_prefix_is_optional() {
+        assert_eq!(render_tree(&["a/b.rs"], NONE), render_tree(&["./a/b.rs"], NONE));
+    }
+
+    #[test]
+    fn no_paths_is_just_the_root() {
+        assert_eq!(render_tree(NONE, NONE), ".\n");
+    }
+
+    #[test]
+    fn empty_dirs_are_marked() {
+        let expected = "\
+.
+├── docs/ (empty)
+└── src
+    ├── main.rs
+    └── old/ (empty)
+";
+        assert_eq!(render_tree(&["./src/main.rs"], &["./src/old", "./docs"]), expected);
+    }
+}
@@ -0,0 +1,54 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::file_processor::FileProcessor;
+
+/// How long the tree must be quiet before re-running, so a burst of events
+/// (an editor saving, a `git checkout`) triggers a single rebuild.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Runs `aggregate` now and again whenever a file it would consider changes.
+/// `aggregate` returns the processor it ran, whose filters decide which
+/// changes matter. Only returns if the watcher can't be set up.
+pub fn watch<F>(working_dir: &Path, clear_screen: bool, mut aggregate: F) -> notify::Result<()>
+where
+    F: FnMut() -> FileProcessor,
+{
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let root = working_dir.canonicalize()?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    let mut processor = aggregate();
+    loop {
+        let mut changed = false;
+        // Block for the first event, then drain until things go quiet
+        let Ok(event) = rx.recv() else {
+            return Ok(());
+        };
+        let mut pending = vec![event];
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            pending.push(event);
+        }
+
+        for event in pending.into_iter().flatten() {
+            // Reading the files ourselves produces access events; reacting to
+            // those would re-run forever
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            if event.paths.iter().any(|path| processor.is_relevant_change(&root, path)) {
+                changed = true;
+            }
+        }
+
+        if changed {
+            if clear_screen {
+                print!("\x1b[2J\x1b[H");
+            }
+            processor = aggregate();
+        }
+    }
+}